@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Lamport's bakery lock, usable before the MMU is enabled.
+//!
+//! `kspin` relies on exclusive or atomic instructions, which are only
+//! guaranteed to work on Normal cacheable memory, while all data accesses
+//! are Device-nGnRnE until a CPU enables its MMU. The bakery algorithm only
+//! needs plain loads and stores.
+//!
+//! A CPU with the MMU off accesses memory uncached while the others go
+//! through their caches, so every access is paired with a clean and
+//! invalidate to the point of coherency, and each CPU writes to its own
+//! cache line only.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::config::plat::CPU_NUM;
+
+/// Polls of another CPU before going ahead anyway, so that a CPU stopped
+/// while holding the lock does not hang the others.
+const WAIT_TIMEOUT: usize = 1_000_000;
+
+/// State of a CPU, alone in its cache line.
+#[repr(C, align(64))]
+struct Slot {
+    /// The CPU is picking its number.
+    choosing: AtomicU32,
+    /// Number of the CPU in the queue, 0 if it does not want the lock.
+    number: AtomicU32,
+}
+
+/// Lock for up to `CPU_NUM` CPUs, indexed by their MPIDR affinity 0.
+pub(crate) struct BakeryLock {
+    slots: [Slot; CPU_NUM],
+}
+
+/// Cleans and invalidates the cache line of `ptr` to the point of coherency.
+fn sync_line<T>(ptr: *const T) {
+    unsafe {
+        core::arch::asm!(
+            "dmb sy",
+            "dc civac, {}",
+            "dsb sy",
+            in(reg) ptr,
+        )
+    };
+}
+
+fn read(cell: &AtomicU32) -> u32 {
+    sync_line(cell);
+    cell.load(Ordering::Relaxed)
+}
+
+fn write(cell: &AtomicU32, val: u32) {
+    cell.store(val, Ordering::Relaxed);
+    sync_line(cell);
+}
+
+/// A value shared between CPUs with and without their MMU, alone in its
+/// cache line.
+#[repr(C, align(64))]
+pub(crate) struct SyncCell(AtomicU32);
+
+impl SyncCell {
+    pub(crate) const fn new(val: u32) -> Self {
+        Self(AtomicU32::new(val))
+    }
+
+    pub(crate) fn get(&self) -> u32 {
+        read(&self.0)
+    }
+
+    pub(crate) fn set(&self, val: u32) {
+        write(&self.0, val)
+    }
+}
+
+impl BakeryLock {
+    pub(crate) const fn new() -> Self {
+        Self {
+            slots: [const {
+                Slot {
+                    choosing: AtomicU32::new(0),
+                    number: AtomicU32::new(0),
+                }
+            }; CPU_NUM],
+        }
+    }
+
+    /// Takes the lock for `cpu`, which must be below `CPU_NUM`.
+    ///
+    /// Returns `false` without waiting if `cpu` already holds the lock.
+    pub(crate) fn lock(&self, cpu: usize) -> bool {
+        let me = &self.slots[cpu];
+        if read(&me.number) != 0 {
+            return false;
+        }
+        write(&me.choosing, 1);
+        let mut max = 0;
+        for slot in &self.slots {
+            let number = read(&slot.number);
+            if number > max {
+                max = number;
+            }
+        }
+        let mine = max + 1;
+        write(&me.number, mine);
+        write(&me.choosing, 0);
+
+        for (other, slot) in self.slots.iter().enumerate() {
+            if other == cpu {
+                continue;
+            }
+            for _ in 0..WAIT_TIMEOUT {
+                if read(&slot.choosing) == 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            for _ in 0..WAIT_TIMEOUT {
+                let number = read(&slot.number);
+                if number == 0 || number > mine || (number == mine && other > cpu) {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+        true
+    }
+
+    /// Releases the lock held by `cpu`.
+    pub(crate) fn unlock(&self, cpu: usize) {
+        write(&self.slots[cpu].number, 0);
+    }
+}
@@ -3,67 +3,531 @@
 // Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
 // See LICENSE for license details.
 
-use axplat::mem::{Aligned4K, pa};
-use page_table_entry::{GenericPTE, MappingFlags, aarch64::A64PTE};
+use axplat::mem::{pa, phys_to_virt, va, virt_to_phys};
+use page_table_entry::{GenericPTE, MappingFlags, aarch64::{A64PTE, MemAttr}};
 use aarch64_cpu::registers::*;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::config::plat::{BOOT_STACK_SIZE, PHYS_VIRT_OFFSET};
+use crate::config::devices::MMIO_RANGES;
+use crate::config::plat::{
+    BOOT_STACK_SIZE, KERNEL_BASE_PADDR, PHYS_MEMORY_BASE, PHYS_MEMORY_SIZE, PHYS_VIRT_OFFSET,
+};
 
+/// Size of the translation granule, selected by the `granule-16k` and
+/// `granule-64k` features (4K by default).
+#[cfg(feature = "granule-64k")]
+pub(crate) const GRANULE_SIZE: usize = 1 << 16;
+#[cfg(all(feature = "granule-16k", not(feature = "granule-64k")))]
+pub(crate) const GRANULE_SIZE: usize = 1 << 14;
+#[cfg(not(any(feature = "granule-16k", feature = "granule-64k")))]
+pub(crate) const GRANULE_SIZE: usize = 1 << 12;
+
+const GRANULE_SHIFT: usize = GRANULE_SIZE.trailing_zeros() as usize;
+const PT_ENTRIES: usize = GRANULE_SIZE / 8;
+
+/// Returns the size mapped by an entry at the given level.
+const fn level_size(level: usize) -> usize {
+    1 << (GRANULE_SHIFT + (GRANULE_SHIFT - 3) * (3 - level))
+}
+
+/// First level of a 48-bit walk: level 0, or level 1 with 64K granules.
+const ROOT_LEVEL: usize = if GRANULE_SHIFT == 16 { 1 } else { 0 };
+
+/// Returns whether a block can be mapped at the given level with 48-bit
+/// output addresses.
+const fn block_allowed(level: usize) -> bool {
+    level == 2 || (level == 1 && GRANULE_SHIFT == 12)
+}
+
+/// Smallest block size (2M, 32M or 512M), MMIO regions are expanded to it.
+const BLOCK_SIZE: usize = level_size(2);
+
+/// Physical memory covered by the boot page table (48-bit).
+const BOOT_PT_PA_LIMIT: usize = 1 << 48;
+
+macro_rules! granule_aligned {
+    ($align:literal) => {
+        /// A translation table of the boot page table.
+        #[repr(C, align($align))]
+        struct PageTable([A64PTE; PT_ENTRIES]);
+
+        /// The boot stack of the primary CPU, with a guard page below it.
+        #[repr(C, align($align))]
+        struct BootStack {
+            guard: [u8; GRANULE_SIZE],
+            stack: [u8; BOOT_STACK_SIZE],
+        }
+    };
+}
+
+#[cfg(feature = "granule-64k")]
+granule_aligned!(0x10000);
+#[cfg(all(feature = "granule-16k", not(feature = "granule-64k")))]
+granule_aligned!(0x4000);
+#[cfg(not(any(feature = "granule-16k", feature = "granule-64k")))]
+granule_aligned!(0x1000);
+
+// Not cleared with `.bss`, and the guard page is left unmapped in the boot
+// page table (not in the kernel page tables).
 #[unsafe(link_section = ".bss.stack")]
-static mut BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+static mut BOOT_STACK: BootStack = BootStack {
+    guard: [0; GRANULE_SIZE],
+    stack: [0; BOOT_STACK_SIZE],
+};
+
+impl PageTable {
+    const fn new() -> Self {
+        Self([A64PTE::empty(); PT_ENTRIES])
+    }
+}
+
+#[unsafe(link_section = ".data")]
+static mut BOOT_PT_ROOT: PageTable = PageTable::new();
+
+/// Number of tables available to the boot page table, besides the root.
+#[cfg(feature = "granule-64k")]
+const BOOT_PT_POOL_SIZE: usize = 12;
+#[cfg(all(feature = "granule-16k", not(feature = "granule-64k")))]
+const BOOT_PT_POOL_SIZE: usize = 16;
+#[cfg(not(any(feature = "granule-16k", feature = "granule-64k")))]
+const BOOT_PT_POOL_SIZE: usize = 24;
 
 #[unsafe(link_section = ".data")]
-static mut BOOT_PT_L0: Aligned4K<[A64PTE; 512]> = Aligned4K::new([A64PTE::empty(); 512]);
+static mut BOOT_PT_POOL: [PageTable; BOOT_PT_POOL_SIZE] =
+    [const { PageTable::new() }; BOOT_PT_POOL_SIZE];
 
 #[unsafe(link_section = ".data")]
-static mut BOOT_PT_L1: Aligned4K<[A64PTE; 512]> = Aligned4K::new([A64PTE::empty(); 512]);
+static mut BOOT_PT_POOL_USED: usize = 0;
 
+use crate::boottime::{self, Milestone};
+use crate::cpufeatures::cpu_features;
+use crate::early_trap::{BootStage, set_boot_stage};
+use crate::fdt::early::EarlyFdt;
 use crate::serial::{boot_print_str, boot_print_usize};
 
-unsafe fn init_boot_page_table() {
+unsafe extern "C" {
+    fn _stext();
+    fn _etext();
+    fn _srodata();
+    fn _erodata();
+    fn _sdata();
+    fn _ekernel();
+}
+
+/// Returns whether the MMU is enabled. Before that, the boot page tables
+/// are accessed through their physical address, and through the linear
+/// mapping afterwards.
+fn mmu_enabled() -> bool {
+    SCTLR_EL1.get() & 1 != 0
+}
+
+fn table_ptr(paddr: usize) -> *mut PageTable {
+    if mmu_enabled() {
+        phys_to_virt(pa!(paddr)).as_usize() as _
+    } else {
+        paddr as _
+    }
+}
+
+fn table_paddr(table: &PageTable) -> usize {
+    let addr = table as *const PageTable as usize;
+    if mmu_enabled() {
+        virt_to_phys(va!(addr)).as_usize()
+    } else {
+        addr
+    }
+}
+
+/// Allocates an empty table from the pool.
+unsafe fn boot_pt_alloc() -> Option<&'static mut PageTable> {
+    let used = unsafe { &mut *(&raw mut BOOT_PT_POOL_USED) };
+    if *used == BOOT_PT_POOL_SIZE {
+        boot_print_str("[boot] boot page table pool exhausted\r\n");
+        return None;
+    }
+    let next = unsafe { &mut *(&raw mut BOOT_PT_POOL[*used]) };
+    *used += 1;
+    Some(next)
+}
+
+/// Returns the next-level table referenced by `table[idx]`, allocating it
+/// from the pool if necessary.
+///
+/// Returns `None` if the entry is already mapped by a block, or the pool is
+/// exhausted.
+unsafe fn boot_pt_next(table: &mut PageTable, idx: usize) -> Option<&'static mut PageTable> {
+    let entry = &mut table.0[idx];
+    if entry.is_present() {
+        if entry.is_huge() {
+            return None;
+        }
+        return Some(unsafe { &mut *table_ptr(entry.paddr().as_usize()) });
+    }
+
+    let next = unsafe { boot_pt_alloc()? };
+    *entry = A64PTE::new_table(pa!(table_paddr(next)));
+    Some(next)
+}
+
+/// Guarded page bit of block and page descriptors (BTI).
+#[cfg(feature = "branch-protection")]
+const PTE_GP: u64 = 1 << 50;
+
+/// Creates a block or page descriptor. Executable memory is mapped as
+/// guarded pages if BTI is enabled, in the boot page table only: the kernel
+/// page tables have to set GP themselves.
+fn leaf_pte(paddr: usize, flags: MappingFlags, is_huge: bool) -> A64PTE {
+    let pte = A64PTE::new_page(pa!(paddr), flags, is_huge);
+    #[cfg(feature = "branch-protection")]
+    if flags.contains(MappingFlags::EXECUTE) && crate::pauth::bti_enabled() {
+        // SAFETY: `A64PTE` is a transparent wrapper of the descriptor.
+        return unsafe { core::mem::transmute::<u64, A64PTE>(pte.bits() as u64 | PTE_GP) };
+    }
+    pte
+}
+
+/// Kind of a physical memory region in the boot mapping.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RegionKind {
+    /// Normal memory, read-write, never executable.
+    Ram,
+    /// Device memory, read-write, never executable.
+    Mmio,
+    /// Kernel code, read-only and executable.
+    KernelText,
+    /// Kernel read-only data, never executable.
+    KernelRodata,
+    /// Kernel data and bss, read-write, never executable.
+    KernelData,
+    /// Stack guard page, left unmapped.
+    Guard,
+}
+
+impl RegionKind {
+    /// Returns the mapping flags of the kind. `DEVICE` selects the device
+    /// MAIR index, all other kinds use the normal cacheable one.
+    fn flags(self) -> MappingFlags {
+        match self {
+            Self::Ram => MappingFlags::READ | MappingFlags::WRITE,
+            Self::Mmio => MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            Self::KernelText => MappingFlags::READ | MappingFlags::EXECUTE,
+            Self::KernelRodata => MappingFlags::READ,
+            Self::KernelData => MappingFlags::READ | MappingFlags::WRITE,
+            Self::Guard => MappingFlags::empty(),
+        }
+    }
+
+    /// Kernel sections and guard pages are carved out of the RAM region
+    /// that contains them, instead of being mapped on their own.
+    fn is_kernel_section(self) -> bool {
+        matches!(
+            self,
+            Self::KernelText | Self::KernelRodata | Self::KernelData | Self::Guard
+        )
+    }
+}
+
+const MAX_BOOT_REGIONS: usize = 32;
+
+/// Builder of the boot identity mapping from a list of `(range, kind)`
+/// entries.
+///
+/// The mapping is made of blocks, and pages where a kernel section starts
+/// or ends, so that every section gets exactly the attributes of its kind.
+/// A page shared by several kinds (only if sections are not page aligned)
+/// gets the union of their permissions.
+struct BootMapBuilder {
+    regions: [(usize, usize, RegionKind); MAX_BOOT_REGIONS],
+    len: usize,
+}
+
+impl BootMapBuilder {
+    const fn new() -> Self {
+        Self {
+            regions: [(0, 0, RegionKind::Ram); MAX_BOOT_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Adds the physical range `[base, base + size)` of the given kind.
+    fn add(&mut self, base: usize, size: usize, kind: RegionKind) -> &mut Self {
+        if size == 0 {
+            return self;
+        }
+        if self.len == MAX_BOOT_REGIONS {
+            boot_print_str("[boot] too many boot regions, drop ");
+            boot_print_usize(base);
+            return self;
+        }
+        self.regions[self.len] = (base, size, kind);
+        self.len += 1;
+        self
+    }
+
+    fn regions(&self) -> impl Iterator<Item = (usize, usize, RegionKind)> + '_ {
+        self.regions[..self.len].iter().copied()
+    }
+
+    /// Returns the flags of the block `[start, end)` inside a region of the
+    /// given kind.
+    fn block_flags(&self, kind: RegionKind, start: usize, end: usize) -> MappingFlags {
+        if kind != RegionKind::Ram {
+            return kind.flags();
+        }
+        let mut flags = MappingFlags::empty();
+        let mut covered = 0;
+        for (base, size, section) in self.regions().filter(|r| r.2.is_kernel_section()) {
+            let (lo, hi) = (base.max(start), (base + size).min(end));
+            if lo < hi {
+                flags |= section.flags();
+                covered += hi - lo;
+            }
+        }
+        if covered < end - start {
+            flags |= kind.flags();
+        }
+        flags
+    }
+
+    /// Returns whether the page `[start, end)` is a guard page.
+    fn is_guard(&self, start: usize, end: usize) -> bool {
+        self.regions()
+            .any(|(base, size, kind)| kind == RegionKind::Guard && base <= start && end <= base + size)
+    }
+
+    /// Returns whether a region of the given kind intersects `[start, end)`.
+    fn overlaps(&self, kind: RegionKind, start: usize, end: usize) -> bool {
+        self.regions()
+            .any(|(base, size, k)| k == kind && base < end && start < base + size)
+    }
+
+    /// Returns the kind of the page `[start, end)` while mapping a region of
+    /// the given kind: device memory wins over RAM, so that no device is
+    /// ever mapped as normal memory.
+    fn page_kind(&self, kind: RegionKind, start: usize, end: usize) -> RegionKind {
+        if self.overlaps(RegionKind::Mmio, start, end) {
+            RegionKind::Mmio
+        } else if self.overlaps(RegionKind::Ram, start, end) {
+            RegionKind::Ram
+        } else {
+            kind
+        }
+    }
+
+    /// Returns whether the block `[start, end)` cannot be mapped with a
+    /// single entry: a kernel section starts or ends inside, or it is shared
+    /// by RAM and a device region.
+    fn must_split(&self, start: usize, end: usize) -> bool {
+        let inside = |addr: usize| start < addr && addr < end;
+        self.regions()
+            .any(|(base, size, kind)| kind.is_kernel_section() && (inside(base) || inside(base + size)))
+            || (self.overlaps(RegionKind::Ram, start, end)
+                && self.overlaps(RegionKind::Mmio, start, end))
+    }
+
+    /// Maps the region `[paddr, paddr + size)` of the given kind at
+    /// `paddr + va_offset`. `va_offset` must be aligned to the largest block.
+    ///
+    /// RAM is only expanded to pages, so that nothing around it is mapped as
+    /// normal memory: its unaligned head and tail are mapped with pages. Other
+    /// kinds are expanded to the smallest block size. Blocks shared with a
+    /// region of another kind are mapped with pages of their own kind, see
+    /// [`Self::page_kind`]. Entries that are already mapped are left
+    /// untouched.
+    unsafe fn map_region(&self, paddr: usize, size: usize, kind: RegionKind, va_offset: usize) {
+        let align = if kind == RegionKind::Ram { GRANULE_SIZE } else { BLOCK_SIZE };
+        let start = paddr & !(align - 1);
+        let end = (paddr + size).next_multiple_of(align);
+        if end > BOOT_PT_PA_LIMIT {
+            boot_print_str("[boot] skip mapping above 256T: ");
+            boot_print_usize(paddr);
+        }
+        let root = unsafe { &mut *(&raw mut BOOT_PT_ROOT) };
+        unsafe { self.map_level(root, ROOT_LEVEL, start, end.min(BOOT_PT_PA_LIMIT), kind, va_offset) };
+    }
+
+    /// Maps `[start, end)` into `table`, which is a table at `level`.
+    ///
+    /// Blocks are used whenever the level allows it and no kernel section
+    /// boundary falls inside, otherwise the walk goes down to the next level.
+    unsafe fn map_level(
+        &self,
+        table: &mut PageTable,
+        level: usize,
+        start: usize,
+        end: usize,
+        kind: RegionKind,
+        va_offset: usize,
+    ) {
+        let size = level_size(level);
+        let mut addr = start;
+        while addr < end {
+            let next = (addr / size + 1) * size;
+            let idx = (((addr + va_offset) & (BOOT_PT_PA_LIMIT - 1)) / size) % PT_ENTRIES;
+            let whole = addr % size == 0 && next <= end;
+            let present = table.0[idx].is_present();
+            if level == 3 {
+                if !present && !self.is_guard(addr, next) {
+                    let flags = self.block_flags(self.page_kind(kind, addr, next), addr, next);
+                    table.0[idx] = leaf_pte(addr, flags, false);
+                }
+            } else if whole && !present && block_allowed(level) && !self.must_split(addr, next) {
+                let flags = self.block_flags(kind, addr, next);
+                table.0[idx] = leaf_pte(addr, flags, true);
+            } else if let Some(next_table) = unsafe { boot_pt_next(table, idx) } {
+                let next_end = next.min(end);
+                unsafe { self.map_level(next_table, level + 1, addr, next_end, kind, va_offset) };
+            }
+            addr = next;
+        }
+    }
+
+    /// Returns the end of the highest region.
+    #[cfg(feature = "kaslr")]
+    fn phys_end(&self) -> usize {
+        self.regions().map(|(base, size, _)| base + size).max().unwrap_or(0)
+    }
+
+    /// Fills the boot page table with the identity mapping, and the linear
+    /// mapping if it is moved by `kaslr_offset`.
+    ///
+    /// RAM is mapped first. Blocks shared by RAM and a device region are
+    /// split into pages, and a page shared by both is mapped as device
+    /// memory.
+    unsafe fn build(&self, kaslr_offset: usize) {
+        for va_offset in [0, kaslr_offset] {
+            for kind in [RegionKind::Ram, RegionKind::Mmio] {
+                for (base, size, _) in self.regions().filter(|r| r.2 == kind) {
+                    unsafe { self.map_region(base, size, kind, va_offset) };
+                }
+            }
+            if kaslr_offset == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Adds RAM and MMIO described by the device tree to the boot mapping.
+///
+/// RAM comes from the `/memory` nodes, MMIO from the `reg` of the other
+/// top-level nodes and the memory windows in the `ranges` of PCI host
+/// bridges. Physical ranges that are not described are left unmapped.
+///
+/// Returns `false` if the device tree cannot be parsed.
+fn add_fdt_regions(builder: &mut BootMapBuilder, dtb: usize) -> bool {
+    let Some(fdt) = (unsafe { EarlyFdt::from_paddr(dtb) }) else {
+        return false;
+    };
+
+    fdt.walk(|node| {
+        if node.depth != 1 || !node.is_enabled() {
+            return;
+        }
+        if node.is_memory() {
+            for (base, size) in node.reg() {
+                boot_print_str("[boot] RAM ");
+                boot_print_usize(base);
+                builder.add(base, size, RegionKind::Ram);
+            }
+            return;
+        }
+        for (base, size) in node.reg() {
+            builder.add(base, size, RegionKind::Mmio);
+        }
+        if node.prop("device_type").is_some_and(|t| t.str_eq("pci")) {
+            // (child address, parent address, size), the child address is
+            // always 3 cells for PCI.
+            let Some(ranges) = node.prop("ranges") else {
+                return;
+            };
+            let (ac, sc) = (node.address_cells, node.child_size_cells);
+            let entry = 3 + ac + sc;
+            for i in 0..ranges.len() / (4 * entry) {
+                let cell = i * entry;
+                // space code in bits 24..26 of phys.hi, skip I/O space
+                let space = ranges.u32(cell).map(|hi| (hi >> 24) & 0x3);
+                if space == Some(1) {
+                    continue;
+                }
+                if let (Some(base), Some(size)) =
+                    (ranges.cells(cell + 3, ac), ranges.cells(cell + 3 + ac, sc))
+                {
+                    builder.add(base, size, RegionKind::Mmio);
+                }
+            }
+        }
+    });
+    true
+}
+
+/// Builds the boot page table, and relocates the image with the `pie`
+/// feature.
+///
+/// Returns the offset of the linear mapping.
+unsafe extern "C" fn init_boot_page_table(dtb: usize) -> usize {
+    set_boot_stage(BootStage::PageTable);
+    boottime::record(Milestone::PageTable);
+    unsafe { crate::cpufeatures::init() };
+    crate::serial::init_boot_uart(dtb);
+    #[cfg(feature = "branch-protection")]
+    unsafe { crate::pauth::init(dtb) };
     boot_print_str("[boot] init boot page table\r\n");
-    crate::psci::kvm_guard_granule_init();
+    if !entered_at_el2() {
+        boottime::record(Milestone::MmioGuard);
+        crate::psci::kvm_guard_granule_init();
+
+        boot_print_str("[boot] kvm xmap pci cam\r\n");
+        crate::psci::do_xmap_granules(0x7200_0000, 0x100_0000);
 
-    boot_print_str("[boot] kvm xmap pci cam\r\n");
-    crate::psci::do_xmap_granules(0x7200_0000, 0x100_0000);
+        boot_print_str("[boot] kvm xmap pci mem\r\n");
+        crate::psci::do_xmap_granules(0x7000_0000, 0x200_0000);
 
-    boot_print_str("[boot] kvm xmap pci mem\r\n");
-    crate::psci::do_xmap_granules(0x7000_0000, 0x200_0000);
+        boot_print_str("[boot] kvm xmap gicv3 mem\r\n");
+        crate::psci::do_xmap_granules(0x3ffb_0000, 0x20_0000);
+        boottime::record(Milestone::MmioGuardDone);
+    }
 
-    boot_print_str("[boot] kvm xmap gicv3 mem\r\n");
-    crate::psci::do_xmap_granules(0x3ffb_0000, 0x20_0000);
+    let mut builder = BootMapBuilder::new();
+    if !add_fdt_regions(&mut builder, dtb) {
+        boot_print_str("[boot] invalid FDT, use the configured memory map\r\n");
+        builder.add(PHYS_MEMORY_BASE, PHYS_MEMORY_SIZE, RegionKind::Ram);
+    }
+    // always keep the configured MMIO ranges (e.g. the boot UART) mapped
+    for &(base, size) in MMIO_RANGES.iter() {
+        builder.add(base, size, RegionKind::Mmio);
+    }
+    builder
+        .add(_stext as usize, _etext as usize - _stext as usize, RegionKind::KernelText)
+        .add(
+            _srodata as usize,
+            _erodata as usize - _srodata as usize,
+            RegionKind::KernelRodata,
+        )
+        .add(_sdata as usize, _ekernel as usize - _sdata as usize, RegionKind::KernelData);
 
+    let cpu = MPIDR_EL1.get() as usize & 0xff;
+    let guard = &raw const BOOT_STACK as usize;
+    builder.add(guard, GRANULE_SIZE, RegionKind::Guard);
+    crate::early_trap::set_stack_guard(cpu, guard);
+
+    for (base, size, _) in builder.regions().filter(|r| r.2 == RegionKind::Ram) {
+        unsafe { crate::mem::add_ram_bank(base, size) };
+    }
+
+    #[cfg(feature = "kaslr")]
+    let kaslr_offset = unsafe { crate::kaslr::init(dtb, builder.phys_end()) };
+    #[cfg(not(feature = "kaslr"))]
+    let kaslr_offset = 0;
+    #[cfg(feature = "pie")]
     unsafe {
-        // 0x0000_0000_0000 ~ 0x0080_0000_0000, table
-        BOOT_PT_L0[0] = A64PTE::new_table(pa!(&raw mut BOOT_PT_L1 as usize));
-        // 0x0000_0000_0000..0x0000_4000_0000, 1G block, device memory
-        BOOT_PT_L1[0] = A64PTE::new_page(
-            pa!(0),
-            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
-            true,
-        );
-        // 0x0000_4000_0000..0x0000_8000_0000, 1G block, normal memory
-        BOOT_PT_L1[1] = A64PTE::new_page(
-            pa!(0x4000_0000),
-            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
-            true,
-        );
-
-        // 0x0000_8000_0000..0x0000_C000_0000, 1G block, normal memory_set
-        BOOT_PT_L1[2] = A64PTE::new_page(
-            pa!(0x8000_0000),
-            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
-            true,
-        );
-
-        // 0x0000_C000_0000..0x0001_0000_0000, 1G block, normal memory_set
-        BOOT_PT_L1[3] = A64PTE::new_page(
-            pa!(0xC000_0000),
-            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
-            true,
-        );
+        set_boot_stage(BootStage::Relocate);
+        crate::reloc::relocate(kaslr_offset);
     }
 
+    unsafe { builder.build(kaslr_offset) };
+    boottime::record(Milestone::PageTableDone);
+    PHYS_VIRT_OFFSET + kaslr_offset
 }
 
 #[unsafe(no_mangle)]
@@ -71,6 +535,93 @@ extern "C" fn kernel_main_test() {
     boot_print_str("[boot] kernel main entered cpu id\r\n");
 }
 
+/// `TCR_EL1` for 48-bit TTBR0/TTBR1 walks on inner shareable write-back
+/// memory, with the configured granule. IPS is filled at runtime.
+const TCR_EL1_BOOT: u64 = {
+    let tg = match GRANULE_SHIFT {
+        12 => (0b00 << 14) | (0b10 << 30),
+        14 => (0b10 << 14) | (0b01 << 30),
+        _ => (0b01 << 14) | (0b11 << 30),
+    };
+    let ttbr0 = 16 | (0b01 << 8) | (0b01 << 10) | (0b11 << 12);
+    let ttbr1 = (16 << 16) | (0b01 << 24) | (0b01 << 26) | (0b11 << 28);
+    tg | ttbr0 | ttbr1
+};
+
+/// Returns whether the CPU supports the configured granule at stage 1.
+fn granule_supported() -> bool {
+    let features = cpu_features();
+    match GRANULE_SHIFT {
+        12 => features.granule_4k,
+        14 => features.granule_16k,
+        _ => features.granule_64k,
+    }
+}
+
+/// Enables the MMU with `root_paddr` as the root table of both TTBR0 and
+/// TTBR1.
+///
+/// Used instead of `axcpu::init::init_mmu`, which only supports 4K granules.
+unsafe extern "C" fn init_mmu(root_paddr: usize) {
+    if !granule_supported() {
+        boot_print_str("[boot] translation granule not supported\r\n");
+        crate::psci::system_off_early();
+    }
+    let mut mair = MemAttr::MAIR_VALUE;
+    // at most 48-bit physical addresses, as the descriptors
+    let ips = (cpu_features().regs.mmfr0 & 0xf).min(0b101);
+    let mut tcr = TCR_EL1_BOOT | (ips << 32);
+    // M, C and I
+    let mut sctlr = SCTLR_EL1.get() | (1 << 0) | (1 << 2) | (1 << 12);
+    #[cfg(feature = "mte")]
+    crate::mte::init_cpu(&mut mair, &mut tcr, &mut sctlr);
+
+    MAIR_EL1.set(mair);
+    TCR_EL1.set(tcr);
+    TTBR0_EL1.set(root_paddr as _);
+    TTBR1_EL1.set(root_paddr as _);
+    unsafe {
+        core::arch::asm!("isb", "tlbi vmalle1", "dsb nsh", "isb");
+    }
+    SCTLR_EL1.set(sctlr);
+    unsafe { core::arch::asm!("isb") };
+}
+
+/// Enables pointer authentication and BTI on the current CPU, with the
+/// `branch-protection` feature.
+///
+/// Naked and called from the entry code only: a function compiled with
+/// `pac-ret` that is running when the keys are enabled would fail to
+/// authenticate its return address. Only clobbers `x9`-`x12`.
+#[unsafe(naked)]
+#[unsafe(link_section = ".text.boot")]
+unsafe extern "C" fn enable_branch_protection() {
+    #[cfg(feature = "branch-protection")]
+    core::arch::naked_asm!("
+        adrp    x9, {state}
+        add     x9, x9, :lo12:{state}
+        ldr     x10, [x9]               // SCTLR_EL1 bits, 0 if unsupported
+        cbz     x10, 2f
+        tbz     x10, #31, 1f            // EnIA: program the keys
+        ldp     x11, x12, [x9, #8]
+        msr     s3_0_c2_c1_0, x11       // APIAKeyLo_EL1
+        msr     s3_0_c2_c1_1, x12       // APIAKeyHi_EL1
+        ldp     x11, x12, [x9, #24]
+        msr     s3_0_c2_c1_2, x11       // APIBKeyLo_EL1
+        msr     s3_0_c2_c1_3, x12       // APIBKeyHi_EL1
+    1:
+        mrs     x11, sctlr_el1
+        orr     x11, x11, x10
+        msr     sctlr_el1, x11
+        isb
+    2:
+        ret",
+        state = sym crate::pauth::BRANCH_PROTECTION,
+    );
+    #[cfg(not(feature = "branch-protection"))]
+    core::arch::naked_asm!("ret");
+}
+
 unsafe fn enable_fp() {
     // FP/SIMD needs to be enabled early, as the compiler may generate SIMD
     // instructions in the bootstrapping code to speed up the operations
@@ -79,6 +630,20 @@ unsafe fn enable_fp() {
     axcpu::asm::enable_fp();
 }
 
+/// Offset of the image from a 2M aligned base, as expected by the loaders.
+const IMAGE_TEXT_OFFSET: usize = KERNEL_BASE_PADDR % (2 << 20);
+
+/// Flags of the image header: little-endian, the page size, and with the
+/// `pie` feature, the image may be placed anywhere in physical memory.
+const IMAGE_FLAGS: usize = (IMAGE_PAGE_SIZE << 1) | if cfg!(feature = "pie") { 1 << 3 } else { 0 };
+
+/// Page size field of the image header: 1 = 4K, 2 = 16K, 3 = 64K.
+const IMAGE_PAGE_SIZE: usize = match GRANULE_SHIFT {
+    12 => 1,
+    14 => 2,
+    _ => 3,
+};
+
 /// Kernel entry point with Linux image header.
 ///
 /// Some bootloaders require this header to be present at the beginning of the
@@ -90,25 +655,154 @@ unsafe fn enable_fp() {
 #[unsafe(link_section = ".text.boot")]
 unsafe extern "C" fn _start() -> ! {
     core::arch::naked_asm!("
-         bl       {entry}             // Branch to kernel start, magic
-        .space 52, 0
-        .inst 0x644d5241
-        .space 4, 0
+        b       {entry}                 // code0: branch to kernel start
+        .long   0                       // code1
+        .quad   {text_offset}           // image load offset
+        .quad   _ekernel - _start       // effective image size, with bss
+        .quad   {flags}                 // kernel flags
+        .quad   0                       // reserved
+        .quad   0                       // reserved
+        .quad   0                       // reserved
+        .inst   0x644d5241              // magic, ARM\\x64
+        .long   0                       // reserved
     ",
     entry = sym _start_primary,
+    text_offset = const IMAGE_TEXT_OFFSET,
+    flags = const IMAGE_FLAGS,
+    )
+}
+
+/// Switches to EL1 if the CPU is entered at EL2, and selects `SP_ELx`.
+///
+/// The stack pointer is carried over, and execution continues at the caller
+/// at EL1 with all exceptions masked. Shared by the primary and secondary
+/// entry paths; only clobbers `x9` and `x10`.
+///
+/// With the `vhe` feature, a CPU entered at EL2 that implements VHE stays
+/// at EL2 with `HCR_EL2.{E2H, TGE}` set instead, so the kernel runs in the
+/// EL2&0 translation regime and its `*_EL1` register accesses are redirected
+/// to the EL2 registers.
+#[unsafe(naked)]
+#[unsafe(link_section = ".text.boot")]
+unsafe extern "C" fn switch_to_el1() {
+    core::arch::naked_asm!("
+        mov     x9, sp                  // use SP_ELx at every exception level
+        msr     spsel, #1
+        mov     sp, x9
+
+        mrs     x9, currentel
+        lsr     x9, x9, #2
+        cmp     x9, #2
+        b.ne    1f                      // only EL2 is handled
+
+        adrp    x9, {entered_at_el2}    // no hypervisor above the kernel
+        add     x9, x9, :lo12:{entered_at_el2}
+        mov     w10, #1
+        strb    w10, [x9]
+        dc      civac, x9               // seen by the CPUs with the MMU on
+        msr     cntvoff_el2, xzr        // virtual count = physical count
+
+        mrs     x9, icc_sre_el2         // ICC_SRE_EL2.{SRE, Enable}: use
+        orr     x9, x9, #0x9            // the GICv3 system registers at EL2
+        msr     icc_sre_el2, x9         // and EL1
+        isb
+
+        mov     x9, #{vhe}
+        cbz     x9, 2f
+        mrs     x9, id_aa64mmfr1_el1    // ID_AA64MMFR1_EL1.VH
+        ubfx    x9, x9, #8, #4
+        cbz     x9, 2f
+        ldr     x9, ={hcr_el2_vhe}      // stay at EL2 with E2H and TGE
+        msr     hcr_el2, x9
+        isb
+        mrs     x9, cnthctl_el2         // EL0PCTEN | EL0VCTEN in the E2H
+        orr     x9, x9, #3              // layout: let EL0 read the counters
+        msr     cnthctl_el2, x9
+        isb
+        ret
+
+    2:
+        mov     x9, sp
+        msr     sp_el1, x9              // keep the stack at EL1
+
+        ldr     x9, ={hcr_el2}          // HCR_EL2.RW: EL1 is AArch64, no traps
+        msr     hcr_el2, x9
+
+        mrs     x9, cnthctl_el2         // EL1PCEN | EL1PCTEN: let EL1 use
+        orr     x9, x9, #3              // the physical timer and counter
+        msr     cnthctl_el2, x9
+
+        mov     x9, #0x33ff             // CPTR_EL2: don't trap FP/SIMD
+        msr     cptr_el2, x9
+        msr     hstr_el2, xzr
+
+        mrs     x9, midr_el1            // EL1 sees the real CPU ids
+        msr     vpidr_el2, x9
+        mrs     x9, mpidr_el1
+        msr     vmpidr_el2, x9
+
+        msr     vttbr_el2, xzr          // no stage 2 translation
+        ldr     x9, ={sctlr_el1}        // EL1 starts with the MMU off
+        msr     sctlr_el1, x9
+
+        mov     x9, #0x3c5              // SPSR_EL2: EL1h, DAIF masked
+        msr     spsr_el2, x9
+        msr     elr_el2, lr
+        eret
+    1:
+        ret",
+        entered_at_el2 = sym ENTERED_AT_EL2,
+        sctlr_el1 = const SCTLR_EL1_MMU_OFF,
+        vhe = const cfg!(feature = "vhe") as u64,
+        hcr_el2 = const HCR_EL2_RW | HCR_EL2_PAUTH | HCR_EL2_MTE,
+        hcr_el2_vhe = const HCR_EL2_VHE | HCR_EL2_PAUTH | HCR_EL2_MTE,
     )
 }
 
-// 根据需要切换至EL1
-unsafe fn switch_to_el1() {
-    let current_sp = aarch64_cpu::registers::SP.get();
-    // 各异常级别使用不同SP
-    SPSel.write(SPSel::SP::ELx);
-    aarch64_cpu::registers::SP.set(current_sp);
-    // 根据需要切换至EL1
-    let current_el = CurrentEL.read(CurrentEL::EL);
+/// `HCR_EL2.RW`: EL1 is AArch64.
+const HCR_EL2_RW: u64 = 1 << 31;
+
+/// `HCR_EL2.{E2H, RW, TGE}`: host the kernel at EL2.
+const HCR_EL2_VHE: u64 = (1 << 34) | HCR_EL2_RW | (1 << 27);
+
+/// `HCR_EL2.{API, APK}`: don't trap the pointer authentication instructions
+/// and key registers, with the `branch-protection` feature.
+const HCR_EL2_PAUTH: u64 = if cfg!(feature = "branch-protection") {
+    (1 << 41) | (1 << 40)
+} else {
+    0
+};
+
+/// `HCR_EL2.ATA`: don't trap the allocation tag accesses, with the `mte`
+/// feature.
+const HCR_EL2_MTE: u64 = if cfg!(feature = "mte") { 1 << 56 } else { 0 };
+
+/// Returns whether the kernel runs at EL2 (see the `vhe` feature).
+pub(crate) fn is_el2() -> bool {
+    CurrentEL.read(CurrentEL::EL) == 2
+}
+
+/// Set by [`switch_to_el1`] on a CPU entered at EL2.
+// Written with the MMU off, before `.bss` is cleared.
+#[unsafe(link_section = ".data")]
+static ENTERED_AT_EL2: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the kernel was entered at EL2, whether it stays there or
+/// drops to EL1.
+///
+/// There is no hypervisor above the kernel in that case: PSCI calls must use
+/// `smc`, and the pKVM hypercalls are not available.
+pub(crate) fn entered_at_el2() -> bool {
+    is_el2() || ENTERED_AT_EL2.load(Ordering::Relaxed)
+}
+
+/// RES1 bits of `SCTLR_EL1`, with the MMU and caches disabled.
+const SCTLR_EL1_MMU_OFF: u64 = (1 << 29) | (1 << 28) | (1 << 23) | (1 << 22) | (1 << 20) | (1 << 11);
+
+/// Prints the exception level after [`switch_to_el1`].
+unsafe extern "C" fn print_current_el() {
     boot_print_str("[boot] Current el ");
-    boot_print_usize(current_el as _);
+    boot_print_usize(CurrentEL.read(CurrentEL::EL) as _);
 }
 
 /// The earliest entry point for the primary CPU.
@@ -117,39 +811,65 @@ unsafe fn switch_to_el1() {
 unsafe extern "C" fn _start_primary() -> ! {
     // X0 = dtb
     core::arch::naked_asm!("
+        adrp    x8, early_exception_vectors
+        add     x8, x8, :lo12:early_exception_vectors
+        msr     vbar_el1, x8            // report faults until init_trap
+        mrs     x8, currentel
+        cmp     x8, #0x8                // and at EL2, until the drop to EL1
+        b.ne    1f
+        adrp    x8, early_exception_vectors_el2
+        add     x8, x8, :lo12:early_exception_vectors_el2
+        msr     vbar_el2, x8
+        msr     cntvoff_el2, xzr        // before the first milestone
+    1:  isb
+
         mrs     x19, mpidr_el1
         and     x19, x19, #0xffffff     // get current CPU id
         mov     x20, x0                 // save DTB pointer
 
         adrp    x8, {boot_stack}        // setup boot stack
-        add     x8, x8, {boot_stack_size}
+        add     x8, x8, {boot_stack_top}
         mov     sp, x8
+        mov     x0, {milestone_entry}
+        bl      {record_milestone}
 
         bl      {switch_to_el1}         // switch to EL1
+        bl      {print_current_el}
         bl      {enable_fp}             // enable fp/neon
 
+        mov     x0, x20
         bl      {init_boot_page_table}
+        mov     x21, x0                 // offset of the linear mapping
+        mov     x0, {stage_mmu}
+        bl      {set_boot_stage}
         adrp    x0, {boot_pt}
         bl      {init_mmu}              // setup MMU
+        bl      {enable_branch_protection}
 
-        mov     x8, {phys_virt_offset}  // set SP to the high address
-        add     sp, sp, x8
+        add     sp, sp, x21             // set SP to the high address
 
+        adrp    x8, {entry}             // no absolute address, the image
+        add     x8, x8, :lo12:{entry}   // may have been relocated
+        add     x8, x8, x21
         mov     x0, x19                 // call_main(cpu_id, dtb)
         mov     x1, x20
-        ldr     x8, ={entry}
         blr     x8
         b .
         ",
         switch_to_el1 = sym switch_to_el1,
+        print_current_el = sym print_current_el,
+        set_boot_stage = sym set_boot_stage,
+        stage_mmu = const BootStage::Mmu as usize,
+        record_milestone = sym boottime::record,
+        milestone_entry = const Milestone::Entry as usize,
         init_boot_page_table = sym init_boot_page_table,
-        init_mmu = sym axcpu::init::init_mmu,
+        init_mmu = sym init_mmu,
+        enable_branch_protection = sym enable_branch_protection,
         enable_fp = sym enable_fp,
-        boot_pt = sym BOOT_PT_L0,
-        phys_virt_offset = const PHYS_VIRT_OFFSET,
+        boot_pt = sym BOOT_PT_ROOT,
         entry = sym axplat::call_main,
         boot_stack = sym BOOT_STACK,
-        boot_stack_size = const BOOT_STACK_SIZE,
+        boot_stack_top = const GRANULE_SIZE + BOOT_STACK_SIZE,
     )
 }
 
@@ -158,29 +878,52 @@ unsafe extern "C" fn _start_primary() -> ! {
 #[unsafe(naked)]
 #[unsafe(link_section = ".text.boot")]
 pub(crate) unsafe extern "C" fn _start_secondary() -> ! {
-    // X0 = stack pointer
+    // X0 = stack pointer passed to `cpu_boot`, in physical address
     core::arch::naked_asm!("
+        adrp    x8, early_exception_vectors
+        add     x8, x8, :lo12:early_exception_vectors
+        msr     vbar_el1, x8            // report faults until init_trap
+        mrs     x8, currentel
+        cmp     x8, #0x8                // and at EL2, until the drop to EL1
+        b.ne    1f
+        adrp    x8, early_exception_vectors_el2
+        add     x8, x8, :lo12:early_exception_vectors_el2
+        msr     vbar_el2, x8
+    1:  isb
+
         mrs     x19, mpidr_el1
         and     x19, x19, #0xffffff     // get current CPU id
 
         mov     sp, x0
+        mov     x0, {stage_secondary}
+        bl      {set_boot_stage}
         bl      {switch_to_el1}
         bl      {enable_fp}
         adrp    x0, {boot_pt}
         bl      {init_mmu}
+        bl      {enable_branch_protection}
 
-        mov     x8, {phys_virt_offset}  // set SP to the high address
-        add     sp, sp, x8
+        adrp    x8, {kaslr_offset}      // offset of the linear mapping
+        ldr     x21, [x8, :lo12:{kaslr_offset}]
+        mov     x8, {phys_virt_offset}
+        add     x21, x21, x8
+        add     sp, sp, x21             // set SP to the high address
 
+        adrp    x8, {entry}
+        add     x8, x8, :lo12:{entry}
+        add     x8, x8, x21
         mov     x0, x19                 // call_secondary_main(cpu_id)
-        ldr     x8, ={entry}
         blr     x8
         b      .",
-        switch_to_el1 = sym axcpu::init::switch_to_el1,
-        init_mmu = sym axcpu::init::init_mmu,
+        switch_to_el1 = sym switch_to_el1,
+        set_boot_stage = sym set_boot_stage,
+        stage_secondary = const BootStage::Secondary as usize,
+        init_mmu = sym init_mmu,
+        enable_branch_protection = sym enable_branch_protection,
         enable_fp = sym enable_fp,
-        boot_pt = sym BOOT_PT_L0,
+        boot_pt = sym BOOT_PT_ROOT,
         phys_virt_offset = const PHYS_VIRT_OFFSET,
+        kaslr_offset = sym crate::kaslr::KASLR_OFFSET,
         entry = sym axplat::call_secondary_main,
     )
 }
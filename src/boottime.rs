@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Boot milestones timed with the generic counter.
+//!
+//! The primary CPU records `CNTVCT_EL0` when it reaches each [`Milestone`],
+//! and the timings are printed once logging is up.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use aarch64_cpu::registers::*;
+use log::info;
+
+/// Points of the primary CPU boot path.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Milestone {
+    /// `_start_primary`, once the boot stack is set up.
+    Entry = 0,
+    /// `init_boot_page_table` is entered.
+    PageTable,
+    /// MMIO guard setup (pKVM hypercalls) starts.
+    MmioGuard,
+    /// MMIO guard setup is done.
+    MmioGuardDone,
+    /// The boot page table is built.
+    PageTableDone,
+    /// `init_early` is entered.
+    InitEarly,
+    /// `init_later` is entered.
+    InitLater,
+}
+
+impl Milestone {
+    const COUNT: usize = 7;
+
+    const ALL: [Self; Self::COUNT] = [
+        Self::Entry,
+        Self::PageTable,
+        Self::MmioGuard,
+        Self::MmioGuardDone,
+        Self::PageTableDone,
+        Self::InitEarly,
+        Self::InitLater,
+    ];
+
+    /// Returns a short description of the milestone.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Entry => "entry",
+            Self::PageTable => "boot page table",
+            Self::MmioGuard => "MMIO guard",
+            Self::MmioGuardDone => "MMIO guard done",
+            Self::PageTableDone => "page table done",
+            Self::InitEarly => "init early",
+            Self::InitLater => "init later",
+        }
+    }
+}
+
+// Written before `.bss` is cleared, 0 if the milestone is not reached.
+#[unsafe(link_section = ".data")]
+static MILESTONES: [AtomicU64; Milestone::COUNT] = [const { AtomicU64::new(0) }; Milestone::COUNT];
+
+/// Records the current counter value for `milestone`.
+pub(crate) extern "C" fn record(milestone: Milestone) {
+    MILESTONES[milestone as usize].store(CNTVCT_EL0.get(), Ordering::Relaxed);
+}
+
+/// Returns the counter value recorded at `milestone`, if it was reached.
+pub fn milestone_ticks(milestone: Milestone) -> Option<u64> {
+    match MILESTONES[milestone as usize].load(Ordering::Relaxed) {
+        0 => None,
+        ticks => Some(ticks),
+    }
+}
+
+/// Returns the time from the kernel entry to `milestone`, in nanoseconds.
+pub fn milestone_nanos(milestone: Milestone) -> Option<u64> {
+    let start = milestone_ticks(Milestone::Entry)?;
+    let ticks = milestone_ticks(milestone)?.saturating_sub(start);
+    let freq = CNTFRQ_EL0.get().max(1);
+    Some((ticks as u128 * 1_000_000_000 / freq as u128) as u64)
+}
+
+/// Prints the boot timings with the time spent since the previous milestone.
+pub(crate) fn print_report() {
+    info!("boot timings:");
+    let mut prev = 0;
+    for milestone in Milestone::ALL {
+        let Some(nanos) = milestone_nanos(milestone) else {
+            continue;
+        };
+        info!(
+            "  {:<16} {:>8} us (+{} us)",
+            milestone.name(),
+            nanos / 1000,
+            (nanos - prev) / 1000
+        );
+        prev = nanos;
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Console on the boot UART.
+//!
+//! Reads go through the RX queue of the console serial port, filled by its
+//! interrupt handler with the `irq` feature (see [`crate::serial_port`]).
+//! Before `init_later`, they poll the UART. Writes are synchronous, so that
+//! nothing is lost on a panic.
+//!
+//! With the `virtio-console` feature, the console moves to the
+//! virtio-console device once it is ready (see [`crate::virtio_console`]),
+//! the UART is then only the early console.
+
+use axplat::console::ConsoleIf;
+use kspin::SpinNoIrq;
+
+use crate::serial::boot_uart;
+use crate::serial_port::console_port;
+
+/// Serializes the writers, so that lines are not interleaved.
+static TX_LOCK: SpinNoIrq<()> = SpinNoIrq::new(());
+
+/// Reads the received bytes into `buf` without blocking.
+///
+/// Returns the number of bytes read.
+pub fn read(buf: &mut [u8]) -> usize {
+    #[cfg(feature = "virtio-console")]
+    if crate::virtio_console::is_ready() {
+        return crate::virtio_console::read(buf);
+    }
+    match console_port() {
+        Some(port) => port.read(buf),
+        None => {
+            let uart = boot_uart();
+            let mut len = 0;
+            while len < buf.len() {
+                let Some(byte) = uart.get() else {
+                    break;
+                };
+                buf[len] = byte;
+                len += 1;
+            }
+            len
+        }
+    }
+}
+
+/// Reads at least one byte into `buf`, waiting for interrupts in between.
+///
+/// Returns the number of bytes read, 0 only if `buf` is empty. The
+/// virtio-console device is polled.
+pub fn read_blocking(buf: &mut [u8]) -> usize {
+    #[cfg(feature = "virtio-console")]
+    if crate::virtio_console::is_ready() {
+        loop {
+            let len = read(buf);
+            if len > 0 || buf.is_empty() {
+                return len;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    match console_port() {
+        Some(port) => port.read_blocking(buf),
+        None => loop {
+            let len = read(buf);
+            if len > 0 || buf.is_empty() {
+                return len;
+            }
+            core::hint::spin_loop();
+        },
+    }
+}
+
+/// Reads a line into `buf`, blocking until a carriage return or a line feed
+/// is received or `buf` is full.
+///
+/// Returns the length of the line, without the terminator.
+pub fn read_line(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    let mut byte = 0;
+    while len < buf.len() {
+        read_blocking(core::slice::from_mut(&mut byte));
+        if byte == b'\r' || byte == b'\n' {
+            break;
+        }
+        buf[len] = byte;
+        len += 1;
+    }
+    len
+}
+
+/// Writes bytes to the console, converting `\n` to `\r\n`.
+pub fn write(bytes: &[u8]) {
+    #[cfg(feature = "virtio-console")]
+    if crate::virtio_console::write(bytes) {
+        return;
+    }
+    let _guard = TX_LOCK.lock();
+    let uart = boot_uart();
+    for &byte in bytes {
+        if byte == b'\n' {
+            uart.put(b'\r');
+        }
+        uart.put(byte);
+    }
+}
+
+struct ConsoleIfImpl;
+
+#[impl_plat_interface]
+impl ConsoleIf for ConsoleIfImpl {
+    /// Writes given bytes to the console.
+    fn write_bytes(bytes: &[u8]) {
+        write(bytes);
+    }
+
+    /// Reads bytes from the console into the given mutable slice.
+    ///
+    /// Returns the number of bytes read.
+    fn read_bytes(bytes: &mut [u8]) -> usize {
+        read(bytes)
+    }
+
+    /// Returns the IRQ number for the console, if applicable.
+    ///
+    /// The UART interrupt is handled by the platform, see
+    /// [`crate::serial_port`].
+    #[cfg(feature = "irq")]
+    fn irq_num() -> Option<usize> {
+        None
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! CPU features decoded from the AArch64 ID registers.
+//!
+//! The registers are read once by the primary CPU while building the boot
+//! page table, [`cpu_features`] returns the decoded values afterwards. All
+//! CPUs are assumed to implement the same features.
+
+/// Raw values of the ID registers the features are decoded from.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IdRegs {
+    /// `ID_AA64PFR0_EL1`.
+    pub pfr0: u64,
+    /// `ID_AA64PFR1_EL1`.
+    pub pfr1: u64,
+    /// `ID_AA64ISAR0_EL1`.
+    pub isar0: u64,
+    /// `ID_AA64ISAR1_EL1`.
+    pub isar1: u64,
+    /// `ID_AA64ISAR2_EL1`.
+    pub isar2: u64,
+    /// `ID_AA64MMFR0_EL1`.
+    pub mmfr0: u64,
+    /// `ID_AA64MMFR1_EL1`.
+    pub mmfr1: u64,
+}
+
+impl IdRegs {
+    /// Reads the ID registers of the current CPU.
+    pub fn read() -> Self {
+        macro_rules! mrs {
+            ($reg:literal) => {{
+                let val: u64;
+                unsafe { core::arch::asm!(concat!("mrs {}, ", $reg), out(reg) val) };
+                val
+            }};
+        }
+        Self {
+            pfr0: mrs!("id_aa64pfr0_el1"),
+            pfr1: mrs!("id_aa64pfr1_el1"),
+            isar0: mrs!("id_aa64isar0_el1"),
+            isar1: mrs!("id_aa64isar1_el1"),
+            isar2: mrs!("id_aa64isar2_el1"),
+            mmfr0: mrs!("id_aa64mmfr0_el1"),
+            mmfr1: mrs!("id_aa64mmfr1_el1"),
+        }
+    }
+}
+
+/// Returns the 4-bit field of `reg` at `shift`.
+const fn field(reg: u64, shift: u32) -> u8 {
+    ((reg >> shift) & 0xf) as u8
+}
+
+/// Features of the CPU.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuFeatures {
+    /// Raw ID register values.
+    pub regs: IdRegs,
+    /// Physical address size in bits.
+    pub pa_bits: u8,
+    /// 16-bit ASIDs.
+    pub asid16: bool,
+    /// 4K translation granule.
+    pub granule_4k: bool,
+    /// 16K translation granule.
+    pub granule_16k: bool,
+    /// 64K translation granule.
+    pub granule_64k: bool,
+    /// EL2 is implemented.
+    pub el2: bool,
+    /// EL3 is implemented.
+    pub el3: bool,
+    /// Virtualization Host Extensions.
+    pub vhe: bool,
+    /// Privileged Access Never.
+    pub pan: bool,
+    /// Hardware update of the access flag.
+    pub hw_access_flag: bool,
+    /// Hardware update of the dirty state.
+    pub hw_dirty: bool,
+    /// Floating point.
+    pub fp: bool,
+    /// Advanced SIMD.
+    pub asimd: bool,
+    /// Scalable Vector Extension.
+    pub sve: bool,
+    /// GIC system register interface.
+    pub gic_sysreg: bool,
+    /// RAS extension.
+    pub ras: bool,
+    /// Large System Extensions (atomic instructions).
+    pub lse: bool,
+    /// CRC32 instructions.
+    pub crc32: bool,
+    /// AES instructions.
+    pub aes: bool,
+    /// PMULL instructions.
+    pub pmull: bool,
+    /// SHA1 instructions.
+    pub sha1: bool,
+    /// SHA256 instructions.
+    pub sha2: bool,
+    /// Random number instructions (`RNDR`/`RNDRRS`).
+    pub rndr: bool,
+    /// Pointer authentication of addresses.
+    pub pauth: bool,
+    /// Generic pointer authentication (`PACGA`).
+    pub pauth_generic: bool,
+    /// Branch Target Identification.
+    pub bti: bool,
+    /// Memory Tagging Extension level: 0 for none, 1 for the instructions
+    /// only, 2 for full MTE, 3 for MTE with asymmetric faults.
+    pub mte: u8,
+}
+
+impl CpuFeatures {
+    /// Decodes the features from the raw ID register values.
+    pub const fn decode(regs: IdRegs) -> Self {
+        let pa_bits = match field(regs.mmfr0, 0) {
+            0 => 32,
+            1 => 36,
+            2 => 40,
+            3 => 42,
+            4 => 44,
+            5 => 48,
+            _ => 52,
+        };
+        let hafdbs = field(regs.mmfr1, 0);
+        Self {
+            regs,
+            pa_bits,
+            asid16: field(regs.mmfr0, 4) == 2,
+            granule_4k: field(regs.mmfr0, 28) != 0xf,
+            granule_16k: field(regs.mmfr0, 20) != 0,
+            granule_64k: field(regs.mmfr0, 24) != 0xf,
+            el2: field(regs.pfr0, 8) != 0,
+            el3: field(regs.pfr0, 12) != 0,
+            vhe: field(regs.mmfr1, 8) != 0,
+            pan: field(regs.mmfr1, 20) != 0,
+            hw_access_flag: hafdbs >= 1,
+            hw_dirty: hafdbs >= 2,
+            fp: field(regs.pfr0, 16) != 0xf,
+            asimd: field(regs.pfr0, 20) != 0xf,
+            sve: field(regs.pfr0, 32) != 0,
+            gic_sysreg: field(regs.pfr0, 24) != 0,
+            ras: field(regs.pfr0, 28) != 0,
+            lse: field(regs.isar0, 20) >= 2,
+            crc32: field(regs.isar0, 16) != 0,
+            aes: field(regs.isar0, 4) != 0,
+            pmull: field(regs.isar0, 4) >= 2,
+            sha1: field(regs.isar0, 8) != 0,
+            sha2: field(regs.isar0, 12) != 0,
+            rndr: field(regs.isar0, 60) != 0,
+            // APA, API, or APA3 (QARMA3) in ID_AA64ISAR2_EL1
+            pauth: field(regs.isar1, 4) != 0
+                || field(regs.isar1, 8) != 0
+                || field(regs.isar2, 12) != 0,
+            // GPA, GPI, or GPA3
+            pauth_generic: field(regs.isar1, 24) != 0
+                || field(regs.isar1, 28) != 0
+                || field(regs.isar2, 8) != 0,
+            bti: field(regs.pfr1, 0) != 0,
+            mte: field(regs.pfr1, 8),
+        }
+    }
+}
+
+// Written before `.bss` is cleared.
+#[unsafe(link_section = ".data")]
+static mut CPU_FEATURES: Option<CpuFeatures> = None;
+
+/// Reads and decodes the ID registers of the boot CPU.
+///
+/// # Safety
+///
+/// Must be called once, before any other CPU is started.
+pub(crate) unsafe fn init() {
+    unsafe { *(&raw mut CPU_FEATURES) = Some(CpuFeatures::decode(IdRegs::read())) };
+}
+
+/// Returns the features of the CPU.
+///
+/// Decodes the ID registers of the current CPU if called before the boot
+/// CPU has recorded them.
+pub fn cpu_features() -> CpuFeatures {
+    match unsafe { *(&raw const CPU_FEATURES) } {
+        Some(features) => features,
+        None => CpuFeatures::decode(IdRegs::read()),
+    }
+}
@@ -0,0 +1,270 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Crash reports for kernel panics and fatal exceptions.
+//!
+//! A report dumps the general purpose registers, the exception and
+//! translation system registers and the GIC CPU interface of the crashing
+//! CPU, then stops the system with an exit the VMM can tell apart from a
+//! power off: crosvm exits with status 32 instead of 0, see
+//! [`crate::psci`].
+//!
+//! The report is printed on the boot UART with polled writes and without
+//! `kspin`, so that it goes out even if the crash happened while holding the
+//! console lock or with a broken virtio-console. Except for the panic
+//! message, nothing is formatted: the early exception handler uses it with
+//! the MMU off.
+//!
+//! Exceptions taken through the early vectors, until the kernel installs
+//! its own, are reported with [`report_exception`]. With the `panic-handler`
+//! feature, this crate defines the `#[panic_handler]` of the kernel with
+//! [`report_panic`], which also covers the exceptions the kernel trap
+//! handler panics on; the kernel must then not define its own. Without it,
+//! the kernel panic handler has to call [`report_panic`] itself.
+
+use core::panic::PanicInfo;
+
+use crate::serial::{boot_print_dec, boot_print_str};
+
+/// General purpose registers of the crashing context.
+///
+/// The layout is used by the early exception vectors.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CrashRegs {
+    /// `x0` to `x30`.
+    pub x: [u64; 31],
+    /// Stack pointer.
+    pub sp: u64,
+    /// Program counter (`ELR_EL1` for an exception).
+    pub elr: u64,
+    /// Processor state (`SPSR_EL1` for an exception).
+    pub spsr: u64,
+}
+
+impl CrashRegs {
+    /// Captures the registers of the caller.
+    ///
+    /// `x8` to `x11` are used as scratch registers: `x8` holds the address
+    /// of the result, and the program counter is the one of the capture.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut regs = Self::default();
+        unsafe {
+            core::arch::asm!(
+                "stp    x0, x1, [x8, #0x00]",
+                "stp    x2, x3, [x8, #0x10]",
+                "stp    x4, x5, [x8, #0x20]",
+                "stp    x6, x7, [x8, #0x30]",
+                "stp    x8, x9, [x8, #0x40]",
+                "stp    x10, x11, [x8, #0x50]",
+                "stp    x12, x13, [x8, #0x60]",
+                "stp    x14, x15, [x8, #0x70]",
+                "stp    x16, x17, [x8, #0x80]",
+                "stp    x18, x19, [x8, #0x90]",
+                "stp    x20, x21, [x8, #0xa0]",
+                "stp    x22, x23, [x8, #0xb0]",
+                "stp    x24, x25, [x8, #0xc0]",
+                "stp    x26, x27, [x8, #0xd0]",
+                "stp    x28, x29, [x8, #0xe0]",
+                "mov    x9, sp",
+                "stp    x30, x9, [x8, #0xf0]",
+                // PSTATE in the SPSR layout: NZCV, DAIF, and M = ELx with SP_ELx
+                "mrs    x10, nzcv",
+                "mrs    x11, daif",
+                "orr    x10, x10, x11",
+                "mrs    x11, currentel",
+                "orr    x10, x10, x11",
+                "orr    x10, x10, #1",
+                "adr    x9, .",
+                "stp    x9, x10, [x8, #0x100]",
+                in("x8") &raw mut regs,
+                out("x9") _,
+                out("x10") _,
+                out("x11") _,
+            );
+        }
+        regs
+    }
+}
+
+/// Prints `num` as 16 hexadecimal digits with a `0x` prefix.
+fn print_hex64(num: u64) {
+    let mut msg = [b'0'; 18];
+    msg[1] = b'x';
+    for i in 0..16 {
+        let digit = ((num >> (60 - 4 * i)) & 0xf) as u8;
+        msg[2 + i] = match digit {
+            n if n < 10 => n + b'0',
+            n => n - 10 + b'a',
+        };
+    }
+    // Only ASCII digits.
+    boot_print_str(unsafe { core::str::from_utf8_unchecked(&msg) });
+}
+
+/// Prints `name: value` padded to a column.
+fn print_reg(name: &str, val: u64) {
+    boot_print_str(name);
+    for _ in name.len()..10 {
+        boot_print_str(" ");
+    }
+    boot_print_str(": ");
+    print_hex64(val);
+}
+
+macro_rules! read_sysreg {
+    ($reg:literal) => {{
+        let val: u64;
+        unsafe { core::arch::asm!(concat!("mrs {}, ", $reg), out(reg) val) };
+        val
+    }};
+}
+
+// No tables of names: they would hold linear addresses, not usable with
+// the MMU off.
+
+/// Ends a column of a two-column dump.
+fn print_column_end(column: usize) {
+    boot_print_str(if column % 2 == 1 { "\r\n" } else { "    " });
+}
+
+fn print_gprs(regs: &CrashRegs) {
+    for i in 0..29 {
+        boot_print_str("x");
+        boot_print_dec(i);
+        boot_print_str(if i < 10 { "        : " } else { "       : " });
+        print_hex64(regs.x[i]);
+        print_column_end(i);
+    }
+    print_reg("x29 (fp)", regs.x[29]);
+    print_column_end(29);
+    print_reg("x30 (lr)", regs.x[30]);
+    print_column_end(30);
+    print_reg("sp", regs.sp);
+    print_column_end(31);
+    print_reg("pc", regs.elr);
+    print_column_end(32);
+    print_reg("pstate", regs.spsr);
+    print_column_end(33);
+}
+
+fn print_system_regs() {
+    let mut column = 0;
+    macro_rules! sysreg {
+        ($name:literal, $val:expr) => {
+            print_reg($name, $val);
+            print_column_end(column);
+            column += 1;
+        };
+    }
+    sysreg!("ESR_EL1", read_sysreg!("esr_el1"));
+    sysreg!("FAR_EL1", read_sysreg!("far_el1"));
+    sysreg!("ELR_EL1", read_sysreg!("elr_el1"));
+    sysreg!("SPSR_EL1", read_sysreg!("spsr_el1"));
+    sysreg!("SCTLR_EL1", read_sysreg!("sctlr_el1"));
+    sysreg!("TCR_EL1", read_sysreg!("tcr_el1"));
+    sysreg!("TTBR0_EL1", read_sysreg!("ttbr0_el1"));
+    sysreg!("TTBR1_EL1", read_sysreg!("ttbr1_el1"));
+    sysreg!("MAIR_EL1", read_sysreg!("mair_el1"));
+    sysreg!("VBAR_EL1", read_sysreg!("vbar_el1"));
+    sysreg!("MPIDR_EL1", read_sysreg!("mpidr_el1"));
+    sysreg!("CurrentEL", read_sysreg!("currentel") >> 2);
+    let _ = column;
+}
+
+/// Prints the GIC CPU interface state.
+///
+/// The interrupt acknowledge registers are not read, as that would take
+/// the pending interrupt.
+fn print_gic_cpu_if() {
+    if !crate::cpufeatures::cpu_features().gic_sysreg {
+        boot_print_str("GIC system register interface not implemented\r\n");
+        return;
+    }
+    let sre = read_sysreg!("ICC_SRE_EL1");
+    print_reg("ICC_SRE", sre);
+    // The other ICC registers are only accessible with the system register
+    // interface enabled.
+    if sre & 1 == 0 {
+        print_column_end(1);
+        return;
+    }
+    let mut column = 0;
+    print_column_end(column);
+    column += 1;
+    macro_rules! sysreg {
+        ($name:literal, $val:expr) => {
+            print_reg($name, $val);
+            print_column_end(column);
+            column += 1;
+        };
+    }
+    sysreg!("ICC_CTLR", read_sysreg!("ICC_CTLR_EL1"));
+    sysreg!("ICC_PMR", read_sysreg!("ICC_PMR_EL1"));
+    sysreg!("ICC_RPR", read_sysreg!("ICC_RPR_EL1"));
+    sysreg!("ICC_BPR1", read_sysreg!("ICC_BPR1_EL1"));
+    sysreg!("ICC_AP1R0", read_sysreg!("ICC_AP1R0_EL1"));
+    sysreg!("ICC_HPPIR1", read_sysreg!("ICC_HPPIR1_EL1"));
+    sysreg!("ICC_IGRPEN1", read_sysreg!("ICC_IGRPEN1_EL1"));
+    let _ = column;
+}
+
+fn print_cpu() {
+    boot_print_dec((read_sysreg!("mpidr_el1") & 0xff) as usize);
+}
+
+/// Dumps the registers of the crashing CPU.
+pub(crate) fn dump(regs: &CrashRegs) {
+    boot_print_str("General purpose registers:\r\n");
+    print_gprs(regs);
+    boot_print_str("System registers:\r\n");
+    print_system_regs();
+    boot_print_str("GIC CPU interface:\r\n");
+    print_gic_cpu_if();
+}
+
+/// Reports a kernel panic and stops the system.
+pub fn report_panic(info: &PanicInfo) -> ! {
+    let regs = CrashRegs::capture();
+    boot_print_str("\r\n[crash] kernel panic on CPU ");
+    print_cpu();
+    boot_print_str("\r\n");
+    // Formats the message, only a marker is printed with the MMU off.
+    crate::boot_println!("{}", info);
+    dump(&regs);
+    boot_print_str("[crash] end of report\r\n");
+    crate::psci::system_crash_early()
+}
+
+#[cfg(all(feature = "panic-handler", not(test)))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    // Plain load and store: the MMU may still be off.
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+    if PANICKED.load(Ordering::Relaxed) {
+        // Panicked while reporting a panic.
+        crate::psci::system_crash_early()
+    }
+    PANICKED.store(true, Ordering::Relaxed);
+    report_panic(info)
+}
+
+/// Reports an unexpected exception taken with the registers `regs` and
+/// stops the system.
+///
+/// `what` describes the exception, e.g. "synchronous exception at EL1".
+pub fn report_exception(what: &str, regs: &CrashRegs) -> ! {
+    boot_print_str("\r\n[crash] ");
+    boot_print_str(what);
+    boot_print_str(" on CPU ");
+    print_cpu();
+    boot_print_str("\r\n");
+    dump(regs);
+    boot_print_str("[crash] end of report\r\n");
+    crate::psci::system_crash_early()
+}
@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Ring buffer of the boot messages printed before the logger exists.
+//!
+//! Every byte sent to the boot UART is also stored here, until the buffer is
+//! replayed through `log` in `init_later`. Only the last
+//! [`EARLY_LOG_SIZE`] bytes are kept. Writers are serialized by the boot
+//! console lock, as the UART itself, and the buffer is only accessed through
+//! raw pointers.
+
+use log::info;
+
+/// Size of the ring buffer in bytes.
+const EARLY_LOG_SIZE: usize = 0x4000;
+
+/// Longest line replayed at once, longer lines are split.
+const MAX_LINE: usize = 256;
+
+struct EarlyLog {
+    buf: [u8; EARLY_LOG_SIZE],
+    /// Number of bytes written since boot.
+    pos: usize,
+    replayed: bool,
+}
+
+// Written before `.bss` is cleared.
+#[unsafe(link_section = ".data")]
+static mut EARLY_LOG: EarlyLog = EarlyLog {
+    buf: [0; EARLY_LOG_SIZE],
+    pos: 0,
+    replayed: false,
+};
+
+/// Stores a byte printed to the boot UART.
+///
+/// Called with the boot console lock held.
+pub(crate) fn push(byte: u8) {
+    let log = &raw mut EARLY_LOG;
+    unsafe {
+        if (*log).replayed {
+            return;
+        }
+        let pos = (*log).pos;
+        (*log).buf[pos % EARLY_LOG_SIZE] = byte;
+        (*log).pos = pos + 1;
+    }
+}
+
+fn log_line(line: &[u8]) {
+    if !line.is_empty() {
+        info!("[early] {}", core::str::from_utf8(line).unwrap_or("<invalid utf-8>"));
+    }
+}
+
+/// Replays the stored messages through `log`, line by line, and stops
+/// recording.
+pub(crate) fn replay() {
+    let log = &raw mut EARLY_LOG;
+    // Nothing is written to the buffer once `replayed` is set.
+    let replayed = crate::serial::with_boot_console(|| unsafe {
+        let replayed = (*log).replayed;
+        (*log).replayed = true;
+        replayed
+    });
+    if replayed {
+        return;
+    }
+
+    let pos = unsafe { (*log).pos };
+    let start = pos.saturating_sub(EARLY_LOG_SIZE);
+    if start > 0 {
+        info!("[early] {} bytes of early log lost", start);
+    }
+    let mut line = [0; MAX_LINE];
+    let mut len = 0;
+    for i in start..pos {
+        match unsafe { (*log).buf[i % EARLY_LOG_SIZE] } {
+            b'\n' => {
+                log_line(&line[..len]);
+                len = 0;
+            }
+            b'\r' => {}
+            byte => {
+                if len == MAX_LINE {
+                    log_line(&line[..len]);
+                    len = 0;
+                }
+                line[len] = byte;
+                len += 1;
+            }
+        }
+    }
+    log_line(&line[..len]);
+}
@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Exception vectors used from the kernel entry until `init_trap`.
+//!
+//! Any exception taken during that window is fatal: the handler prints the
+//! boot stage it happened in and a crash report (see [`crate::crash`]), then
+//! stops the system through PSCI. The handler may run with the MMU off, so
+//! it only uses the boot UART and does not format anything.
+//!
+//! The vectors switch to a per-CPU emergency stack first, so that a fault
+//! in the guard page below a boot stack can still be reported. The guard
+//! page of the primary boot stack is only left unmapped in the boot page
+//! table, it is gone once the kernel installs its own page tables. The
+//! secondary CPUs run on the stacks of the kernel, whose bottom is known
+//! only if registered with [`crate::set_secondary_stack_bottom`]: the page
+//! below is checked but not unmapped.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use aarch64_cpu::registers::*;
+
+use crate::config::plat::CPU_NUM;
+use crate::crash::CrashRegs;
+use crate::serial::{boot_print_str, boot_print_usize};
+
+/// Size of the per-CPU stacks used by the early exception handler.
+const EMERGENCY_STACK_SIZE: usize = 0x1000;
+
+#[repr(C, align(16))]
+struct EmergencyStack([u8; EMERGENCY_STACK_SIZE]);
+
+static mut EMERGENCY_STACKS: [EmergencyStack; CPU_NUM] =
+    [const { EmergencyStack([0; EMERGENCY_STACK_SIZE]) }; CPU_NUM];
+
+/// Physical address of the guard page below the boot stack of each CPU,
+/// indexed by `MPIDR_EL1` affinity 0, or 0 if unknown.
+#[unsafe(link_section = ".data")]
+static STACK_GUARDS: [AtomicUsize; CPU_NUM] = [const { AtomicUsize::new(0) }; CPU_NUM];
+
+/// Boot stages reported by the early exception handler.
+#[repr(usize)]
+#[derive(Clone, Copy)]
+pub(crate) enum BootStage {
+    /// From the kernel entry to the boot page table.
+    Entry = 0,
+    /// Building the boot page table.
+    PageTable = 1,
+    /// Relocating the kernel image.
+    #[cfg(feature = "pie")]
+    Relocate = 2,
+    /// Enabling the MMU and jumping to the high address.
+    Mmu = 3,
+    /// Platform early initialization, before `init_trap`.
+    InitEarly = 4,
+    /// Secondary CPU bring-up.
+    Secondary = 5,
+}
+
+// Written before `.bss` is cleared.
+#[unsafe(link_section = ".data")]
+static BOOT_STAGE: AtomicUsize = AtomicUsize::new(BootStage::Entry as usize);
+
+/// Records the current boot stage.
+pub(crate) extern "C" fn set_boot_stage(stage: BootStage) {
+    BOOT_STAGE.store(stage as usize, Ordering::Relaxed);
+}
+
+/// Records the guard page below the boot stack of the CPU with affinity 0
+/// `cpu`.
+pub(crate) fn set_stack_guard(cpu: usize, guard_paddr: usize) {
+    if let Some(guard) = STACK_GUARDS.get(cpu) {
+        guard.store(guard_paddr, Ordering::Relaxed);
+        // Read by the CPU with its MMU possibly off.
+        unsafe { core::arch::asm!("dc civac, {}", "dsb sy", in(reg) guard) };
+    }
+}
+
+/// Returns the CPU whose stack guard page contains `addr`, which is either
+/// a physical address or in the linear mapping.
+fn stack_overflow_cpu(addr: usize) -> Option<usize> {
+    let paddr = addr.wrapping_sub(crate::kaslr::phys_virt_offset());
+    STACK_GUARDS.iter().position(|guard| {
+        let guard = guard.load(Ordering::Relaxed);
+        let in_guard = |a: usize| a.wrapping_sub(guard) < crate::boot::GRANULE_SIZE;
+        guard != 0 && (in_guard(addr) || in_guard(paddr))
+    })
+}
+
+fn print_stage(stage: usize) {
+    match stage {
+        0 => boot_print_str("entry"),
+        1 => boot_print_str("boot page table"),
+        2 => boot_print_str("relocation"),
+        3 => boot_print_str("MMU"),
+        4 => boot_print_str("init early"),
+        5 => boot_print_str("secondary CPU"),
+        _ => boot_print_str("unknown"),
+    }
+}
+
+fn print_vector(vector: usize) {
+    match vector / 4 {
+        0 => boot_print_str("current EL with SP0, "),
+        1 => boot_print_str("current EL with SPx, "),
+        2 => boot_print_str("lower EL (AArch64), "),
+        _ => boot_print_str("lower EL (AArch32), "),
+    }
+    match vector % 4 {
+        0 => boot_print_str("synchronous\r\n"),
+        1 => boot_print_str("IRQ\r\n"),
+        2 => boot_print_str("FIQ\r\n"),
+        _ => boot_print_str("SError\r\n"),
+    }
+}
+
+/// Handles an exception taken through the early vectors.
+///
+/// The registers at the time of the exception are saved by the vectors in
+/// `regs`, on the emergency stack.
+extern "C" fn early_trap_handler(vector: usize, regs: &CrashRegs) -> ! {
+    let far = FAR_EL1.get() as usize;
+    let sp = regs.sp as usize;
+    if let Some(cpu) = stack_overflow_cpu(far).or_else(|| stack_overflow_cpu(sp)) {
+        boot_print_str("\r\n[boot] stack overflow on CPU ");
+        boot_print_usize(cpu);
+    }
+    boot_print_str("\r\n[boot] unexpected exception during boot: ");
+    print_vector(vector);
+    boot_print_str("    stage    = ");
+    print_stage(BOOT_STAGE.load(Ordering::Relaxed));
+    boot_print_str("\r\n");
+    crate::crash::report_exception("early exception", regs)
+}
+
+core::arch::global_asm!(
+    "
+    // The faulting SP is kept in SP_EL0, and the handler runs on the
+    // emergency stack of the CPU. TPIDRRO_EL0 is used as a scratch register,
+    // the handler never returns. The vectors push x0 and x1, then
+    // early_trap_save completes the CrashRegs frame.
+    //
+    // The EL2 vectors cover the window before the drop to EL1 (and the whole
+    // boot with VHE): they copy the EL2 syndrome registers to their EL1
+    // counterparts, which the handler reads.
+    .macro EARLY_VECTOR idx, el2=0
+    .balign 0x80
+        msr     tpidrro_el0, x0
+    .if \\el2
+        mrs     x0, esr_el2
+        msr     esr_el1, x0
+        mrs     x0, far_el2
+        msr     far_el1, x0
+        mrs     x0, elr_el2
+        msr     elr_el1, x0
+        mrs     x0, spsr_el2
+        msr     spsr_el1, x0
+    .endif
+        mov     x0, sp
+        msr     sp_el0, x0
+        mrs     x0, mpidr_el1
+        and     x0, x0, #0xff
+        cmp     x0, {cpu_num}
+        b.lo    1f
+        mov     x0, #0
+    1:  add     x0, x0, #1
+        lsl     x0, x0, {stack_shift}
+        mov     sp, x0
+        adrp    x0, {stacks}
+        add     x0, x0, :lo12:{stacks}
+        add     sp, sp, x0
+        mrs     x0, tpidrro_el0
+        stp     x0, x1, [sp, #-16]!
+        mov     x0, #\\idx
+        b       early_trap_save
+    .endm
+
+    .pushsection .text.boot, \"ax\"
+    .balign 0x800
+    .global early_exception_vectors
+early_exception_vectors:
+    EARLY_VECTOR 0
+    EARLY_VECTOR 1
+    EARLY_VECTOR 2
+    EARLY_VECTOR 3
+    EARLY_VECTOR 4
+    EARLY_VECTOR 5
+    EARLY_VECTOR 6
+    EARLY_VECTOR 7
+    EARLY_VECTOR 8
+    EARLY_VECTOR 9
+    EARLY_VECTOR 10
+    EARLY_VECTOR 11
+    EARLY_VECTOR 12
+    EARLY_VECTOR 13
+    EARLY_VECTOR 14
+    EARLY_VECTOR 15
+
+    .balign 0x800
+    .global early_exception_vectors_el2
+early_exception_vectors_el2:
+    .irp idx, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+    EARLY_VECTOR \\idx, 1
+    .endr
+
+    // x0 = vector index, x0 and x1 of the exception on the stack.
+early_trap_save:
+    sub     sp, sp, #{frame_size} - 16
+    stp     x2, x3, [sp, #0x10]
+    stp     x4, x5, [sp, #0x20]
+    stp     x6, x7, [sp, #0x30]
+    stp     x8, x9, [sp, #0x40]
+    stp     x10, x11, [sp, #0x50]
+    stp     x12, x13, [sp, #0x60]
+    stp     x14, x15, [sp, #0x70]
+    stp     x16, x17, [sp, #0x80]
+    stp     x18, x19, [sp, #0x90]
+    stp     x20, x21, [sp, #0xa0]
+    stp     x22, x23, [sp, #0xb0]
+    stp     x24, x25, [sp, #0xc0]
+    stp     x26, x27, [sp, #0xd0]
+    stp     x28, x29, [sp, #0xe0]
+    ldp     x2, x3, [sp, #{frame_size} - 16]
+    stp     x2, x3, [sp, #0x00]
+    mrs     x2, sp_el0
+    stp     x30, x2, [sp, #0xf0]
+    mrs     x2, elr_el1
+    mrs     x3, spsr_el1
+    stp     x2, x3, [sp, #0x100]
+    mov     x1, sp
+    b       {handler}
+    .popsection
+    ",
+    handler = sym early_trap_handler,
+    frame_size = const core::mem::size_of::<CrashRegs>(),
+    stacks = sym EMERGENCY_STACKS,
+    cpu_num = const CPU_NUM,
+    stack_shift = const EMERGENCY_STACK_SIZE.trailing_zeros(),
+);
@@ -8,6 +8,8 @@ use fdtree_rs::{InterruptController, LinuxFdt};
 use log::*;
 use spin::Once;
 
+pub(crate) mod early;
+
 pub static FDT: Once<LinuxFdt> = Once::new();
 
 pub(crate) fn init_fdt(fdt_paddr: VirtAddr) {
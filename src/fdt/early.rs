@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Minimal flattened device tree reader for the boot stage.
+//!
+//! It is used before the MMU is enabled, when all data accesses are treated
+//! as device memory and unaligned accesses fault. So it never builds slices
+//! over the blob: every access is either an aligned 32-bit load or a single
+//! byte load, and nothing is allocated.
+
+use axplat::mem::RawRange;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+/// Nodes deeper than this are skipped by [`EarlyFdt::walk`].
+const MAX_DEPTH: usize = 16;
+
+const fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+/// A flattened device tree blob accessed through its physical address.
+#[derive(Clone, Copy)]
+pub(crate) struct EarlyFdt {
+    base: usize,
+    off_struct: usize,
+    size_struct: usize,
+    off_strings: usize,
+}
+
+impl EarlyFdt {
+    /// Checks the header of the blob at `paddr`.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` must be accessible at the current translation regime, and the
+    /// blob must stay untouched while the returned value is in use.
+    pub unsafe fn from_paddr(paddr: usize) -> Option<Self> {
+        if paddr == 0 || paddr % 8 != 0 {
+            return None;
+        }
+        let mut fdt = Self {
+            base: paddr,
+            off_struct: 0,
+            size_struct: 0,
+            off_strings: 0,
+        };
+        if fdt.be32(0) != FDT_MAGIC {
+            return None;
+        }
+        fdt.off_struct = fdt.be32(8) as usize;
+        fdt.off_strings = fdt.be32(12) as usize;
+        fdt.size_struct = fdt.be32(36) as usize;
+        Some(fdt)
+    }
+
+    fn be32(&self, off: usize) -> u32 {
+        u32::from_be(unsafe { ((self.base + off) as *const u32).read_volatile() })
+    }
+
+    fn byte(&self, off: usize) -> u8 {
+        unsafe { ((self.base + off) as *const u8).read_volatile() }
+    }
+
+    /// Length of the NUL-terminated string at `off`.
+    fn strlen(&self, off: usize) -> usize {
+        let mut len = 0;
+        while self.byte(off + len) != 0 {
+            len += 1;
+        }
+        len
+    }
+
+    /// Compares the NUL-terminated string at `off` with `s`.
+    fn str_eq(&self, off: usize, s: &str) -> bool {
+        self.bytes_eq(off, s) && self.byte(off + s.len()) == 0
+    }
+
+    /// Compares the bytes at `off` with `s`, ignoring anything after it.
+    fn bytes_eq(&self, off: usize, s: &str) -> bool {
+        s.bytes().enumerate().all(|(i, b)| self.byte(off + i) == b)
+    }
+
+    /// Visits every node of the tree in depth-first order.
+    pub fn walk(&self, mut f: impl FnMut(&EarlyNode<'_>)) {
+        // `cells[d]` holds `#address-cells`/`#size-cells` of the parent of
+        // the nodes at depth `d`. The root node has no parent, the values
+        // below are the defaults from the devicetree specification.
+        let mut cells = [(2, 1); MAX_DEPTH + 1];
+        let mut depth = 0;
+        let mut off = self.off_struct;
+        let end = self.off_struct + self.size_struct;
+
+        while off < end {
+            let token = self.be32(off);
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = off;
+                    off = align4(off + self.strlen(off) + 1);
+                    if depth < MAX_DEPTH {
+                        let (address_cells, size_cells) = cells[depth];
+                        let mut node = EarlyNode {
+                            fdt: self,
+                            depth,
+                            name,
+                            props: off,
+                            address_cells,
+                            size_cells,
+                            child_address_cells: 2,
+                            child_size_cells: 1,
+                        };
+                        if let Some(ac) = node.prop("#address-cells").and_then(|p| p.u32(0)) {
+                            node.child_address_cells = ac as usize;
+                        }
+                        if let Some(sc) = node.prop("#size-cells").and_then(|p| p.u32(0)) {
+                            node.child_size_cells = sc as usize;
+                        }
+                        cells[depth + 1] = (node.child_address_cells, node.child_size_cells);
+                        f(&node);
+                    }
+                    depth += 1;
+                }
+                FDT_END_NODE => {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len = self.be32(off) as usize;
+                    off = align4(off + 8 + len);
+                }
+                FDT_NOP => {}
+                // FDT_END or a corrupted token
+                _ => break,
+            }
+        }
+    }
+}
+
+/// A node visited by [`EarlyFdt::walk`].
+pub(crate) struct EarlyNode<'a> {
+    fdt: &'a EarlyFdt,
+    /// Depth of the node, the root node is at depth 0.
+    pub depth: usize,
+    name: usize,
+    props: usize,
+    /// `#address-cells` of the parent node, used to decode `reg`.
+    pub address_cells: usize,
+    /// `#size-cells` of the parent node, used to decode `reg`.
+    pub size_cells: usize,
+    /// `#address-cells` of this node.
+    pub child_address_cells: usize,
+    /// `#size-cells` of this node.
+    pub child_size_cells: usize,
+}
+
+impl<'a> EarlyNode<'a> {
+    /// Returns whether the node name (including the unit address) starts
+    /// with `prefix`.
+    pub fn name_starts_with(&self, prefix: &str) -> bool {
+        self.fdt.bytes_eq(self.name, prefix)
+    }
+
+    /// Returns whether the node name is `component`, which may omit the unit
+    /// address as in device tree paths.
+    pub fn name_matches(&self, component: &[u8]) -> bool {
+        let fdt = self.fdt;
+        if !component.iter().enumerate().all(|(i, &b)| fdt.byte(self.name + i) == b) {
+            return false;
+        }
+        match fdt.byte(self.name + component.len()) {
+            0 => true,
+            b'@' => !component.contains(&b'@'),
+            _ => false,
+        }
+    }
+
+    /// Looks up a property of the node by name.
+    pub fn prop(&self, name: &str) -> Option<EarlyProp<'a>> {
+        let fdt = self.fdt;
+        let mut off = self.props;
+        loop {
+            match fdt.be32(off) {
+                FDT_PROP => {
+                    let len = fdt.be32(off + 4) as usize;
+                    let name_off = fdt.be32(off + 8) as usize;
+                    let data = off + 12;
+                    if fdt.str_eq(fdt.off_strings + name_off, name) {
+                        return Some(EarlyProp { fdt, off: data, len });
+                    }
+                    off = align4(data + len);
+                }
+                FDT_NOP => off += 4,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Returns whether the node is enabled (no `status` or `status = "okay"`).
+    pub fn is_enabled(&self) -> bool {
+        self.prop("status")
+            .is_none_or(|s| s.str_eq("okay") || s.str_eq("ok"))
+    }
+
+    /// Returns whether the node is a `/memory` node.
+    pub fn is_memory(&self) -> bool {
+        self.depth == 1
+            && (self.prop("device_type").is_some_and(|t| t.str_eq("memory"))
+                || self.name_starts_with("memory"))
+    }
+
+    /// Iterates over the `(base, size)` pairs of the `reg` property.
+    pub fn reg(&self) -> impl Iterator<Item = RawRange> + 'a {
+        let (ac, sc) = (self.address_cells, self.size_cells);
+        let prop = self.prop("reg");
+        let count = match prop {
+            Some(ref p) if ac + sc > 0 => p.len / (4 * (ac + sc)),
+            _ => 0,
+        };
+        (0..count).filter_map(move |i| {
+            let p = prop.as_ref()?;
+            let cell = i * (ac + sc);
+            Some((p.cells(cell, ac)?, p.cells(cell + ac, sc)?))
+        })
+    }
+}
+
+/// A property of an [`EarlyNode`].
+pub(crate) struct EarlyProp<'a> {
+    fdt: &'a EarlyFdt,
+    off: usize,
+    len: usize,
+}
+
+impl EarlyProp<'_> {
+    /// Length of the property value in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Reads the `idx`-th 32-bit cell.
+    pub fn u32(&self, idx: usize) -> Option<u32> {
+        (4 * idx + 4 <= self.len).then(|| self.fdt.be32(self.off + 4 * idx))
+    }
+
+    /// Reads a number encoded as `n` cells starting at cell `idx`.
+    ///
+    /// Returns `None` if the value does not fit into `usize`.
+    pub fn cells(&self, idx: usize, n: usize) -> Option<usize> {
+        if n > 2 && (idx..idx + n - 2).any(|i| self.u32(i) != Some(0)) {
+            return None;
+        }
+        let mut val = 0;
+        for i in idx + n.saturating_sub(2)..idx + n {
+            val = (val << 32) | self.u32(i)? as usize;
+        }
+        Some(val)
+    }
+
+    /// Reads the `idx`-th byte.
+    #[cfg(any(feature = "kaslr", feature = "branch-protection"))]
+    pub fn byte(&self, idx: usize) -> Option<u8> {
+        (idx < self.len).then(|| self.fdt.byte(self.off + idx))
+    }
+
+    /// Overwrites the property value with zeros.
+    ///
+    /// # Safety
+    ///
+    /// The blob must be writable.
+    #[cfg(any(feature = "kaslr", feature = "branch-protection"))]
+    pub unsafe fn wipe(&self) {
+        for i in 0..self.len {
+            unsafe { ((self.fdt.base + self.off + i) as *mut u8).write_volatile(0) };
+        }
+    }
+
+    /// Returns whether a string list property contains `s`.
+    pub fn str_list_contains(&self, s: &str) -> bool {
+        let mut pos = 0;
+        while pos < self.len {
+            if self.len - pos > s.len() && self.fdt.str_eq(self.off + pos, s) {
+                return true;
+            }
+            pos += self.fdt.strlen(self.off + pos) + 1;
+        }
+        false
+    }
+
+    /// Copies a string property into `buf`, without the NUL terminator.
+    ///
+    /// Returns the number of bytes copied, the string is truncated if `buf`
+    /// is too small.
+    pub fn copy_str(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while len < buf.len() && len < self.len {
+            match self.fdt.byte(self.off + len) {
+                0 => break,
+                b => buf[len] = b,
+            }
+            len += 1;
+        }
+        len
+    }
+
+    /// Compares a string property with `s`.
+    pub fn str_eq(&self, s: &str) -> bool {
+        s.len() < self.len && self.fdt.str_eq(self.off, s)
+    }
+}
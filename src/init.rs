@@ -7,11 +7,21 @@ use log::*;
 
 use axplat::init::InitIf;
 #[allow(unused_imports)]
-use crate::config::devices::{GICR_PADDR, GICD_PADDR, TIMER_IRQ, UART_IRQ, UART_PADDR};
+use crate::config::devices::{GICR_PADDR, GICD_PADDR, TIMER_IRQ};
 use crate::config::plat::PSCI_METHOD;
 use axplat::mem::{pa, phys_to_virt};
 
-use crate::serial::*;
+/// PPI of the EL2 physical timer, used when the kernel stays at EL2 (VHE).
+const EL2_TIMER_IRQ: usize = 26;
+
+/// Returns the timer interrupt number.
+///
+/// The `vhe` feature keeps the kernel at EL2 only on CPUs that support it,
+/// so the timer PPI is chosen at runtime: the EL2 physical timer at EL2,
+/// `timer-irq` from the platform config otherwise.
+pub fn timer_irq() -> usize {
+    if crate::boot::is_el2() { EL2_TIMER_IRQ } else { TIMER_IRQ }
+}
 
 struct InitIfImpl;
 
@@ -23,11 +33,14 @@ impl InitIf for InitIfImpl {
     /// and performed earliest platform configuration and initialization (e.g.,
     /// early console, clocking).
     fn init_early(_cpu_id: usize, dtb: usize) {
-        boot_print_str("[boot] platform init early\r\n");
+        crate::early_trap::set_boot_stage(crate::early_trap::BootStage::InitEarly);
+        crate::boottime::record(crate::boottime::Milestone::InitEarly);
+        crate::boot_println!("[boot] platform init early, dtb {:#x}", dtb);
         crate::mem::init_early(dtb);
         axcpu::init::init_trap();
-        axplat_aarch64_peripherals::ns16550a::init_early(phys_to_virt(pa!(UART_PADDR)));
-        axplat_aarch64_peripherals::psci::init(PSCI_METHOD);
+        // entered at EL2, there is no hypervisor to take `hvc`
+        let psci_method = if crate::boot::entered_at_el2() { "smc" } else { PSCI_METHOD };
+        axplat_aarch64_peripherals::psci::init(psci_method);
         axplat_aarch64_peripherals::generic_timer::init_early();
         //#[cfg(feature = "rtc")]
         //axplat_aarch64_peripherals::pl031::init_early(phys_to_virt(pa!(RTC_PADDR)));
@@ -45,9 +58,16 @@ impl InitIf for InitIfImpl {
     /// initialization (e.g, logging, memory management), and finalized the rest of
     /// platform configuration and initialization.
     fn init_later(cpu_id: usize, dtb: usize) {
+        crate::boottime::record(crate::boottime::Milestone::InitLater);
         // now we could use logging
+        crate::early_log::replay();
+        crate::boottime::print_report();
         info!("cpu_id {}", cpu_id);
+        debug!("{:#?}", crate::cpufeatures::cpu_features());
         crate::fdt::init_fdt(phys_to_virt(pa!(dtb)));
+        crate::serial_port::init(phys_to_virt(pa!(dtb)).as_usize());
+        #[cfg(feature = "virtio-console")]
+        crate::virtio_console::init();
 
         #[cfg(feature = "irq")]
         {
@@ -58,9 +78,8 @@ impl InitIf for InitIfImpl {
                 phys_to_virt(pa!(GICR_PADDR)),
             );
             // crosvm set uart as edge trigger irq
-            info!("set UART IRQ {} as edge trigger", UART_IRQ);
-            crate::gicv3::set_trigger(UART_IRQ, true);
-            axplat_aarch64_peripherals::generic_timer::enable_irqs(TIMER_IRQ);
+            crate::serial_port::init_irqs();
+            axplat_aarch64_peripherals::generic_timer::enable_irqs(timer_irq());
         }
     }
 
@@ -73,7 +92,7 @@ impl InitIf for InitIfImpl {
                 phys_to_virt(pa!(GICD_PADDR)),
                 phys_to_virt(pa!(GICR_PADDR)),
             );
-            axplat_aarch64_peripherals::generic_timer::enable_irqs(TIMER_IRQ);
+            axplat_aarch64_peripherals::generic_timer::enable_irqs(timer_irq());
         }
     }
 }
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Kernel address space layout randomization.
+//!
+//! With the `kaslr` feature, the linear mapping, and the kernel image which
+//! lives in it, are moved up by a random offset derived from the
+//! `/chosen/kaslr-seed` and `/chosen/rng-seed` properties of the device tree.
+//! The image is then relocated at boot, so the `kaslr` feature implies the
+//! `pie` one, see the requirements in `src/reloc.rs`.
+
+use crate::config::plat::PHYS_VIRT_OFFSET;
+#[cfg(any(feature = "kaslr", feature = "branch-protection"))]
+use crate::fdt::early::EarlyFdt;
+#[cfg(feature = "kaslr")]
+use crate::serial::{boot_print_str, boot_print_usize};
+
+/// Alignment of the offset, so that the randomized linear mapping can still
+/// be built with 1G blocks.
+#[cfg(feature = "kaslr")]
+const KASLR_ALIGN: usize = 1 << 30;
+
+/// Size of the kernel virtual address space (48-bit).
+#[cfg(feature = "kaslr")]
+const VA_SPACE_SIZE: usize = 1 << 48;
+
+// Written while building the boot page table, before `.bss` is cleared.
+// The secondary CPUs read it right after enabling their MMU.
+#[unsafe(link_section = ".data")]
+pub(crate) static mut KASLR_OFFSET: usize = 0;
+
+/// Returns the offset added by KASLR to the linear mapping and the kernel
+/// image, or 0 if KASLR is disabled.
+pub fn kaslr_offset() -> usize {
+    unsafe { *(&raw const KASLR_OFFSET) }
+}
+
+/// Returns the offset between the linear mapping and physical addresses.
+pub(crate) fn phys_virt_offset() -> usize {
+    PHYS_VIRT_OFFSET + kaslr_offset()
+}
+
+/// splitmix64 finalizer.
+#[cfg(any(feature = "kaslr", feature = "branch-protection"))]
+pub(crate) fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Reads the seeds in `/chosen` and wipes them, so that they cannot be
+/// recovered from the device tree later.
+///
+/// Returns 0 if there is no seed.
+#[cfg(any(feature = "kaslr", feature = "branch-protection"))]
+fn read_seed(fdt: &EarlyFdt) -> u64 {
+    let mut seed = 0;
+    fdt.walk(|node| {
+        if node.depth != 1 || !node.name_starts_with("chosen") {
+            return;
+        }
+        if let Some(prop) = node.prop("kaslr-seed") {
+            seed ^= prop.cells(0, 2).unwrap_or(0) as u64;
+            unsafe { prop.wipe() };
+        }
+        if let Some(prop) = node.prop("rng-seed") {
+            for b in (0..prop.len()).filter_map(|i| prop.byte(i)) {
+                seed = (seed ^ b as u64).wrapping_mul(0x100_0000_01b3);
+            }
+            unsafe { prop.wipe() };
+        }
+    });
+    if seed == 0 { 0 } else { mix(seed) }
+}
+
+/// Hashed device tree seed, kept once the seeds are wiped.
+// Written before `.bss` is cleared.
+#[cfg(any(feature = "kaslr", feature = "branch-protection"))]
+#[unsafe(link_section = ".data")]
+static mut FDT_SEED: Option<u64> = None;
+
+/// Returns the hashed `/chosen` seeds of the device tree at `dtb`, 0 if
+/// there is none.
+///
+/// The first call reads and wipes the seeds, later calls return the kept
+/// value, so that both KASLR and the pointer authentication keys use them.
+///
+/// # Safety
+///
+/// Must only be called by the primary CPU, before the MMU is enabled.
+#[cfg(any(feature = "kaslr", feature = "branch-protection"))]
+pub(crate) unsafe fn fdt_seed(dtb: usize) -> u64 {
+    let kept = unsafe { &mut *(&raw mut FDT_SEED) };
+    if let Some(seed) = *kept {
+        return seed;
+    }
+    let seed = match unsafe { EarlyFdt::from_paddr(dtb) } {
+        Some(fdt) => read_seed(&fdt),
+        None => 0,
+    };
+    *kept = Some(seed);
+    seed
+}
+
+/// Chooses the KASLR offset from the device tree seeds.
+///
+/// `phys_end` is the end of the highest physical range in the linear
+/// mapping. The randomized linear mapping is kept above the identity mapping
+/// of `[0, phys_end)`, since both share the boot page table.
+///
+/// # Safety
+///
+/// Must be called once by the primary CPU, before the MMU is enabled.
+#[cfg(feature = "kaslr")]
+pub(crate) unsafe fn init(dtb: usize, phys_end: usize) -> usize {
+    let seed = unsafe { fdt_seed(dtb) };
+    if seed == 0 {
+        boot_print_str("[boot] no KASLR seed, KASLR disabled\r\n");
+        return 0;
+    }
+
+    let low = phys_end.next_multiple_of(KASLR_ALIGN);
+    let slots = VA_SPACE_SIZE.saturating_sub(2 * low) / KASLR_ALIGN;
+    if slots == 0 {
+        return 0;
+    }
+    let offset = low + (seed as usize % slots) * KASLR_ALIGN;
+    unsafe { *(&raw mut KASLR_OFFSET) = offset };
+    boot_print_str("[boot] KASLR offset ");
+    boot_print_usize(offset);
+    offset
+}
@@ -5,17 +5,44 @@
 
 #![no_std]
 
+#[cfg(all(feature = "granule-16k", feature = "granule-64k"))]
+compile_error!("features `granule-16k` and `granule-64k` are mutually exclusive");
+
 #[macro_use]
 extern crate axplat;
 
+mod bakery;
 mod boot;
+mod early_log;
+mod early_trap;
 mod init;
 mod mem;
 mod power;
 pub mod fdt;
 mod serial;
+pub mod console;
+pub mod serial_port;
+#[cfg(feature = "virtio-console")]
+pub mod virtio_console;
 mod gicv3;
 pub mod psci;
+pub mod kaslr;
+pub mod cpufeatures;
+pub mod boottime;
+pub mod crash;
+#[cfg(feature = "branch-protection")]
+mod pauth;
+#[cfg(feature = "mte")]
+pub mod mte;
+#[cfg(feature = "pie")]
+mod reloc;
+
+pub use init::timer_irq;
+pub use mem::kernel_load_paddr;
+#[cfg(feature = "smp")]
+pub use power::set_secondary_stack_bottom;
+#[doc(hidden)]
+pub use serial::_boot_print;
 
 pub mod config {
     //! Platform configuration module.
@@ -32,7 +59,6 @@ pub mod config {
     );
 }
 
-axplat_aarch64_peripherals::ns16550_console_if_impl!(ConsoleIfImpl);
 axplat_aarch64_peripherals::time_if_impl!(TimeIfImpl);
 
 
@@ -10,7 +10,9 @@ use axplat::mem::{MemIf, PhysAddr, RawRange, VirtAddr, pa, va};
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::config::devices::MMIO_RANGES;
-use crate::config::plat::{PHYS_MEMORY_BASE, PHYS_MEMORY_SIZE, PHYS_VIRT_OFFSET};
+use crate::config::plat::{PHYS_MEMORY_BASE, PHYS_MEMORY_SIZE};
+use crate::kaslr::phys_virt_offset;
+use crate::serial::{boot_print_hex, boot_print_str, boot_print_usize};
 
 // default FDT memory size 2MB
 const FDT_MEM_SIZE: usize = 0x20_0000;
@@ -20,6 +22,45 @@ static FDT_MEM: Once<[RawRange; 2]> = Once::new();
 static DICE_MEM_BASE: AtomicUsize = AtomicUsize::new(0);
 static DICE_MEM_SIZE: AtomicUsize = AtomicUsize::new(0);
 
+const MAX_RAM_BANKS: usize = 16;
+
+// RAM banks are recorded before `.bss` is cleared, so keep them in `.data`.
+#[unsafe(link_section = ".data")]
+static mut RAM_BANKS: [RawRange; MAX_RAM_BANKS] = [(0, 0); MAX_RAM_BANKS];
+
+#[unsafe(link_section = ".data")]
+static mut RAM_BANK_COUNT: usize = 0;
+
+/// Records a RAM bank found in the device tree.
+///
+/// # Safety
+///
+/// Must only be called by the primary CPU during boot.
+pub(crate) unsafe fn add_ram_bank(base: usize, size: usize) {
+    let count = unsafe { &mut *(&raw mut RAM_BANK_COUNT) };
+    if *count < MAX_RAM_BANKS {
+        unsafe { (*(&raw mut RAM_BANKS))[*count] = (base, size) };
+        *count += 1;
+    } else {
+        boot_print_str("[boot] too many RAM banks, drop ");
+        boot_print_hex(base);
+        boot_print_str(" size ");
+        boot_print_usize(size);
+    }
+}
+
+unsafe extern "C" {
+    fn _skernel();
+}
+
+/// Returns the physical address the kernel image has been loaded at.
+///
+/// It is derived from the running image rather than `kernel-base-paddr`,
+/// which the loader does not have to honor with the `pie` feature.
+pub fn kernel_load_paddr() -> usize {
+    axplat::mem::virt_to_phys(va!(_skernel as usize)).as_usize()
+}
+
 /// Initializes the reserved memory physical address.
 pub(crate) fn init_early(fdt_paddr: usize) {
     FDT_MEM_BASE.store(fdt_paddr, Ordering::SeqCst);
@@ -46,7 +87,11 @@ impl MemIf for MemIfImpl {
     /// All memory ranges except reserved ranges (including the kernel loaded
     /// range) are free for allocation.
     fn phys_ram_ranges() -> &'static [RawRange] {
-        &[(PHYS_MEMORY_BASE, PHYS_MEMORY_SIZE)]
+        let count = unsafe { *(&raw const RAM_BANK_COUNT) };
+        if count == 0 {
+            return &[(PHYS_MEMORY_BASE, PHYS_MEMORY_SIZE)];
+        }
+        unsafe { &(*(&raw const RAM_BANKS))[..count] }
     }
 
     /// Returns all reserved physical memory ranges on the platform.
@@ -77,12 +122,12 @@ impl MemIf for MemIfImpl {
 
     /// Translates a physical address to a virtual address.
     fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
-        va!(paddr.as_usize() + PHYS_VIRT_OFFSET)
+        va!(paddr.as_usize() + phys_virt_offset())
     }
 
     /// Translates a virtual address to a physical address.
     fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
-        pa!(vaddr.as_usize() - PHYS_VIRT_OFFSET)
+        pa!(vaddr.as_usize() - phys_virt_offset())
     }
 
     /// Returns the kernel address space base virtual address and size.
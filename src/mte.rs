@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Memory Tagging Extension.
+//!
+//! When the CPU implements MTE with allocation tags (`ID_AA64PFR1_EL1.MTE`
+//! is 2 or more), the normal memory attribute of `MAIR_EL1` is made Tagged,
+//! so that RAM is tagged memory in both the boot page table and the kernel
+//! page table, and tag check faults are taken synchronously at EL1.
+//!
+//! Kernel addresses carry the logical tag `0xf`, and `TCR_EL1.TCMA1` makes
+//! the accesses through them unchecked: the kernel runs unchanged, and only
+//! the pointers produced by [`random_tag`] or [`with_tag`] are checked
+//! against the allocation tags set by [`set_tags`].
+
+use crate::cpufeatures::cpu_features;
+
+/// Size of a tag granule: each aligned 16 bytes of memory have a tag.
+pub const TAG_GRANULE: usize = 16;
+
+/// Logical tag of untagged kernel addresses, never checked.
+pub const MATCH_ALL_TAG: u8 = 0xf;
+
+/// Index of the normal memory attribute in `MAIR_EL1`.
+const MAIR_NORMAL_IDX: u64 = 1;
+/// Tagged normal memory, inner/outer write-back non-transient
+/// read/write-allocate.
+const MAIR_TAGGED_NORMAL: u64 = 0xf0;
+
+const TCR_TBI1: u64 = 1 << 38;
+const TCR_TCMA1: u64 = 1 << 58;
+
+const SCTLR_ATA: u64 = 1 << 43;
+const SCTLR_TCF_SYNC: u64 = 0b01 << 40;
+
+/// Returns whether MTE is enabled.
+pub fn mte_enabled() -> bool {
+    cpu_features().mte >= 2
+}
+
+/// Adjusts the MMU configuration of the current CPU, before its MMU is
+/// enabled.
+pub(crate) fn init_cpu(mair: &mut u64, tcr: &mut u64, sctlr: &mut u64) {
+    if !mte_enabled() {
+        return;
+    }
+    let shift = MAIR_NORMAL_IDX * 8;
+    *mair = (*mair & !(0xff << shift)) | (MAIR_TAGGED_NORMAL << shift);
+    *tcr |= TCR_TBI1 | TCR_TCMA1;
+    *sctlr |= SCTLR_ATA | SCTLR_TCF_SYNC;
+    unsafe {
+        core::arch::asm!(
+            "msr s3_0_c1_c0_6, {gcr}",  // GCR_EL1
+            "msr s3_0_c5_c6_0, xzr",    // TFSR_EL1
+            "msr s3_0_c5_c6_1, xzr",    // TFSRE0_EL1
+            // random tags from the hardware, never the match-all one
+            gcr = in(reg) (1u64 << 16) | (1 << MATCH_ALL_TAG),
+        )
+    };
+}
+
+/// Returns `addr` with the logical tag `tag`.
+pub const fn with_tag(addr: usize, tag: u8) -> usize {
+    (addr & !(0xf << 56)) | (((tag & 0xf) as usize) << 56)
+}
+
+/// Returns the logical tag of `addr`.
+pub const fn tag_of(addr: usize) -> u8 {
+    ((addr >> 56) & 0xf) as u8
+}
+
+/// Returns `addr` with a random logical tag, other than the match-all one.
+///
+/// Returns `addr` unchanged if MTE is disabled.
+pub fn random_tag(addr: usize) -> usize {
+    if !mte_enabled() {
+        return addr;
+    }
+    let tagged: usize;
+    unsafe {
+        core::arch::asm!(
+            ".arch_extension memtag",
+            "irg {0}, {1}",
+            out(reg) tagged,
+            in(reg) addr,
+            options(nostack),
+        )
+    };
+    tagged
+}
+
+/// Sets the allocation tags of `[addr, addr + size)` to the logical tag of
+/// `addr`, so that only pointers with that tag can access the range.
+///
+/// Does nothing if MTE is disabled.
+///
+/// # Safety
+///
+/// The range must be RAM in the linear mapping, with `addr` and `size`
+/// aligned to [`TAG_GRANULE`]. Accesses to the range through pointers with
+/// other tags fault afterwards.
+pub unsafe fn set_tags(addr: usize, size: usize) {
+    if !mte_enabled() {
+        return;
+    }
+    debug_assert!(addr % TAG_GRANULE == 0 && size % TAG_GRANULE == 0);
+    for granule in (addr..addr + size).step_by(TAG_GRANULE) {
+        unsafe {
+            core::arch::asm!(
+                ".arch_extension memtag",
+                "stg {0}, [{0}]",
+                in(reg) granule,
+                options(nostack),
+            )
+        };
+    }
+}
+
+/// Tags `[addr, addr + size)` with `tag` and returns the tagged pointer to
+/// it.
+///
+/// # Safety
+///
+/// See [`set_tags`].
+pub unsafe fn tag_range(addr: usize, size: usize, tag: u8) -> usize {
+    let tagged = with_tag(addr, tag);
+    unsafe { set_tags(tagged, size) };
+    tagged
+}
@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Pointer authentication and BTI for the kernel.
+//!
+//! With the `branch-protection` feature, the primary CPU derives the APIA
+//! and APIB keys from the boot entropy while building the boot page table.
+//! Every CPU then programs the keys and sets `SCTLR_EL1.{EnIA, EnIB, BT1}`
+//! right after enabling the MMU, if it implements the extensions, and the
+//! kernel text is mapped as guarded pages.
+//!
+//! The keys mix `RNDR` when implemented, the `/chosen/rng-seed` and
+//! `kaslr-seed` properties of the device tree (see [`crate::kaslr`]) and the
+//! physical counter. Without `RNDR` nor a device tree seed, they are
+//! predictable.
+//!
+//! Guarded pages are only set in the boot page table: the kernel page tables
+//! must set the GP bit (50) on executable mappings too, otherwise BTI has no
+//! effect once they are installed.
+
+use aarch64_cpu::registers::*;
+
+use crate::cpufeatures::cpu_features;
+use crate::kaslr::{fdt_seed, mix};
+use crate::serial::boot_print_str;
+
+const SCTLR_ENIA: u64 = 1 << 31;
+const SCTLR_ENIB: u64 = 1 << 30;
+const SCTLR_BT1: u64 = 1 << 36;
+
+/// Settings read by `enable_branch_protection` on every CPU.
+#[repr(C)]
+pub(crate) struct BranchProtection {
+    /// Bits to set in `SCTLR_EL1`, 0 if nothing is supported.
+    sctlr: u64,
+    /// APIAKey low and high halves, then APIBKey.
+    keys: [u64; 4],
+}
+
+// Written before `.bss` is cleared, read by every CPU right after its MMU is
+// enabled.
+#[unsafe(link_section = ".data")]
+pub(crate) static mut BRANCH_PROTECTION: BranchProtection = BranchProtection {
+    sctlr: 0,
+    keys: [0; 4],
+};
+
+/// Reads `RNDR`, returns `None` if no random number is available.
+fn rndr() -> Option<u64> {
+    let (val, ok): (u64, u64);
+    unsafe {
+        core::arch::asm!(
+            "mrs {val}, s3_3_c2_c4_0",  // RNDR
+            "cset {ok}, ne",
+            val = out(reg) val,
+            ok = out(reg) ok,
+            options(nomem, nostack),
+        )
+    };
+    (ok != 0).then_some(val)
+}
+
+/// Returns a 64-bit seed from the boot entropy sources.
+fn entropy(dtb: usize) -> u64 {
+    // Domain separation from the KASLR offset, derived from the same seed.
+    let mut seed = CNTPCT_EL0.get() ^ mix(unsafe { fdt_seed(dtb) } ^ 0x7061_7574_6820_6b65);
+    if cpu_features().rndr {
+        for _ in 0..16 {
+            if let Some(val) = rndr() {
+                seed ^= val;
+                break;
+            }
+        }
+    }
+    seed
+}
+
+/// Chooses the keys and the features to enable, `dtb` is the physical
+/// address of the device tree.
+///
+/// # Safety
+///
+/// Must be called once by the primary CPU, before the MMU is enabled.
+pub(crate) unsafe fn init(dtb: usize) {
+    let features = cpu_features();
+    let state = unsafe { &mut *(&raw mut BRANCH_PROTECTION) };
+    if features.pauth {
+        let seed = entropy(dtb);
+        for (i, key) in state.keys.iter_mut().enumerate() {
+            *key = mix(seed.wrapping_add((i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+        }
+        state.sctlr |= SCTLR_ENIA | SCTLR_ENIB;
+        boot_print_str("[boot] pointer authentication enabled\r\n");
+    }
+    if features.bti {
+        state.sctlr |= SCTLR_BT1;
+        boot_print_str("[boot] BTI enabled\r\n");
+    }
+}
+
+/// Returns whether BTI is enabled, so that the kernel text must be mapped as
+/// guarded pages.
+pub(crate) fn bti_enabled() -> bool {
+    unsafe { (*(&raw const BRANCH_PROTECTION)).sctlr & SCTLR_BT1 != 0 }
+}
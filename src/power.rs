@@ -7,6 +7,23 @@ use axplat::power::PowerIf;
 
 struct PowerImpl;
 
+/// Registers the lowest address of the stack that the kernel passes to
+/// `cpu_boot` for `cpu_id`, in physical address.
+///
+/// `cpu_boot` only gets the top of the stack. With the bottom registered
+/// before it, a fault in the page below the stack, or an exception taken
+/// with the stack pointer there, is reported by the early exception handler
+/// as a stack overflow on that CPU. The page is not unmapped, as it may
+/// belong to another stack.
+///
+/// `cpu_id` is the logical CPU ID passed to `cpu_boot`, which is also the
+/// `MPIDR_EL1` affinity 0 of the CPU that PSCI starts.
+#[cfg(feature = "smp")]
+pub fn set_secondary_stack_bottom(cpu_id: usize, bottom_paddr: usize) {
+    let guard = bottom_paddr.wrapping_sub(crate::boot::GRANULE_SIZE);
+    crate::early_trap::set_stack_guard(cpu_id, guard);
+}
+
 #[impl_plat_interface]
 impl PowerIf for PowerImpl {
     /// Bootstraps the given CPU core with the given initial stack (in physical
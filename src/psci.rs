@@ -20,6 +20,27 @@ const ARM_SMCCC_VENDOR_HYP_KVM_MMIO_GUARD_INFO_FUNC_ID: u32 =
 const ARM_SMCCC_VENDOR_HYP_KVM_MEM_SHARE_FUNC_ID: u32 =
     ((1) << 31) | ((1) << 30) | (((6) & 0x3F) << 24) | ((3) & 0xFFFF);
 
+const ARM_SMCCC_VENDOR_HYP_KVM_HYP_MEMINFO_FUNC_ID: u32 =
+    ((1) << 31) | ((1) << 30) | (((6) & 0x3F) << 24) | ((2) & 0xFFFF);
+
+/// Granule of the hypervisor stage-2, in which memory is shared.
+static HYP_GRANULE: Once<usize> = Once::new();
+
+/// Returns the granule MEM_SHARE and MEM_UNSHARE work in, which is the one
+/// of the hypervisor, not the one of the guest.
+///
+/// Falls back to the guest granule if HYP_MEMINFO is not supported.
+fn hyp_granule() -> usize {
+    *HYP_GRANULE.call_once(|| {
+        let (granule, _) = psci_hvc_call(ARM_SMCCC_VENDOR_HYP_KVM_HYP_MEMINFO_FUNC_ID, 0, 0, 0);
+        if granule as isize <= 0 || !granule.is_power_of_two() {
+            log::warn!("HYP_MEMINFO failed ({:#x}), share guest granules", granule);
+            return crate::boot::GRANULE_SIZE;
+        }
+        granule
+    })
+}
+
 pub fn psci_hvc_call(func: u32, arg0: usize, arg1: usize, arg2: usize) -> (usize, usize) {
     let ret0;
     let ret1;
@@ -35,6 +56,46 @@ pub fn psci_hvc_call(func: u32, arg0: usize, arg1: usize, arg2: usize) -> (usize
     (ret0, ret1)
 }
 
+const PSCI_0_2_FN_SYSTEM_OFF: u32 = 0x8400_0008;
+const PSCI_0_2_FN_SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// Issues a PSCI call that does not return, without relying on any
+/// initialized state. The conduit is `smc` if the kernel was entered at
+/// EL2, `hvc` otherwise.
+fn psci_call_early(func: u32) {
+    let func = func as usize;
+    unsafe {
+        if crate::boot::entered_at_el2() {
+            core::arch::asm!("smc #0", inout("x0") func => _);
+        } else {
+            core::arch::asm!("hvc #0", inout("x0") func => _);
+        }
+    }
+}
+
+/// Powers off the system without relying on any initialized state.
+///
+/// Used on fatal errors during boot, when the PSCI driver is not set up yet.
+pub(crate) fn system_off_early() -> ! {
+    psci_call_early(PSCI_0_2_FN_SYSTEM_OFF);
+    loop {
+        aarch64_cpu::asm::wfi();
+    }
+}
+
+/// Stops the system after a crash, with an exit the VMM can tell apart from
+/// a power off.
+///
+/// Uses `SYSTEM_RESET`: crosvm does not reboot the guest but exits with its
+/// `VmReset` status, 32, where a power off exits with 0. The system is left
+/// hanging if the call returns, rather than powered off.
+pub(crate) fn system_crash_early() -> ! {
+    psci_call_early(PSCI_0_2_FN_SYSTEM_RESET);
+    loop {
+        aarch64_cpu::asm::wfi();
+    }
+}
+
 /// 获取KVM的内存保护粒度
 pub fn kvm_guard_granule_init() {
     let (guard_granule, guard_has_range) = psci_hvc_call(ARM_SMCCC_VENDOR_HYP_KVM_MMIO_GUARD_INFO_FUNC_ID, 0, 0, 0);
@@ -98,46 +159,69 @@ pub fn do_xmap_granules(phys_addr: usize, size: usize) {
 }
 
 
+/// Stops sharing the hypervisor granules covering `[paddr, paddr + size)`
+/// with the host. `paddr` must be aligned to the hypervisor granule.
+///
+/// Does nothing when entered at EL2, outside of a protected VM.
+pub fn unshare_dma_buffer(paddr: usize, size: usize) {
+    if crate::boot::entered_at_el2() {
+        return;
+    }
+    let page_size = hyp_granule();
+    let pages = size.div_ceil(page_size);
+    for i in 0..pages {
+        let (ret0, _ret1) = psci_hvc_call(
+            ARM_SMCCC_VENDOR_HYP_KVM_MEM_UNSHARE_FUNC_ID,
+            paddr +  page_size * i,
+            1,
+            0,
+        );
+        if ret0 != 0 {
+            log::warn!(
+                "[virtio hal impl] cannot unshare 0x{:x}",
+                paddr + page_size * i
+            );
+        }
+    }
+}
+
+/// Shares the hypervisor granules covering `[paddr, paddr + size)` with the
+/// host, so that devices can access them. `paddr` must be aligned to the
+/// hypervisor granule.
+///
+/// Does nothing when entered at EL2, outside of a protected VM.
+pub fn share_dma_buffer(paddr: usize, size: usize) {
+    if crate::boot::entered_at_el2() {
+        return;
+    }
+    let page_size = hyp_granule();
+    let pages = size.div_ceil(page_size);
+    for i in 0..pages {
+        let (ret0, _ret1) = psci_hvc_call(
+            ARM_SMCCC_VENDOR_HYP_KVM_MEM_SHARE_FUNC_ID,
+            paddr + page_size * i,
+            1,
+            0,
+        );
+        if ret0 != 0 {
+            log::warn!(
+                "[virtio hal impl] cannot share 0x{:x}",
+                paddr + page_size * i
+            );
+        }
+    }
+}
+
 struct PsciImpl;
 
 #[impl_plat_interface]
 impl PsciIf for PsciImpl {
 
     fn unshare_dma_buffer(paddr: usize, size: usize) {
-        let page_size = 0x1000;
-        let pages = size / page_size;
-        for i in 0..pages {
-            let (ret0, _ret1) = psci_hvc_call(
-                ARM_SMCCC_VENDOR_HYP_KVM_MEM_UNSHARE_FUNC_ID,
-                paddr +  page_size * i,
-                1,
-                0,
-            );
-            if ret0 != 0 {
-                log::warn!(
-                    "[virtio hal impl] cannot unshare 0x{:x}",
-                    paddr + page_size * i
-                );
-            }
-        }
+        self::unshare_dma_buffer(paddr, size);
     }
 
     fn share_dma_buffer(paddr: usize, size: usize) {
-        let page_size = 0x1000;
-        let pages = size / page_size;
-        for i in 0..pages {
-            let (ret0, _ret1) = psci_hvc_call(
-                ARM_SMCCC_VENDOR_HYP_KVM_MEM_SHARE_FUNC_ID,
-                paddr + page_size * i,
-                1,
-                0,
-            );
-            if ret0 != 0 {
-                log::warn!(
-                    "[virtio hal impl] cannot share 0x{:x}",
-                    paddr + page_size * i
-                );
-            }
-        }
+        self::share_dma_buffer(paddr, size);
     }
 }
@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Self-relocation of the kernel image.
+//!
+//! With the `pie` feature, the image can be loaded at any 2M aligned
+//! physical address instead of `kernel-base-paddr`, and it can be moved in
+//! the virtual address space by KASLR. The kernel must be built as a
+//! position-independent executable (`-C relocation-model=pie`), and the
+//! linker script must keep `.rela.dyn` in the loaded image between the
+//! `_srela` and `_erela` symbols.
+
+use crate::config::plat::{KERNEL_BASE_VADDR, PHYS_VIRT_OFFSET};
+
+const R_AARCH64_RELATIVE: u64 = 1027;
+
+/// Size of an `Elf64_Rela` entry.
+const RELA_ENTRY_SIZE: usize = 24;
+
+unsafe extern "C" {
+    fn _skernel();
+    fn _srela();
+    fn _erela();
+}
+
+/// Applies the `R_AARCH64_RELATIVE` relocations of the image, so that it
+/// runs in the linear mapping moved by `kaslr_offset`, wherever it has been
+/// loaded.
+///
+/// # Safety
+///
+/// Must be called once, before the MMU is enabled: the relocated places are
+/// written through their physical addresses, and nothing may have used an
+/// absolute address of the image yet.
+pub(crate) unsafe fn relocate(kaslr_offset: usize) {
+    // the MMU is off, this is the physical load address
+    let load_paddr = _skernel as usize;
+    let delta = (load_paddr + PHYS_VIRT_OFFSET + kaslr_offset).wrapping_sub(KERNEL_BASE_VADDR);
+    if delta == 0 {
+        return;
+    }
+
+    let mut rela = _srela as usize;
+    let end = _erela as usize;
+    while rela < end {
+        let entry = rela as *const u64;
+        let (offset, info, addend) = unsafe {
+            (
+                entry.read_volatile(),
+                entry.add(1).read_volatile(),
+                entry.add(2).read_volatile(),
+            )
+        };
+        if info & 0xffff_ffff == R_AARCH64_RELATIVE {
+            let place = (offset as usize - KERNEL_BASE_VADDR + load_paddr) as *mut usize;
+            unsafe { place.write_volatile((addend as usize).wrapping_add(delta)) };
+        }
+        rela += RELA_ENTRY_SIZE;
+    }
+}
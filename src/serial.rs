@@ -3,40 +3,120 @@
 // Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
 // See LICENSE for license details.
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::bakery::{BakeryLock, SyncCell};
+use crate::config::devices::{UART_IRQ, UART_PADDR};
+use crate::config::plat::CPU_NUM;
+use crate::fdt::early::{EarlyFdt, EarlyNode};
+
 #[unsafe(no_mangle)]
 pub extern "C" fn _boot_print_usize(num: usize) {
-    let mut msg: [u8; 16] = [0; 16];
-    let mut num = num;
-    let mut cnt = 0;
+    let mut console = BootConsole::lock();
+    console.put_str("0x");
+    console.put_digits(num, 16);
+    console.put_str("\r\n");
+}
 
-    boot_print_str("0x");
-    if num == 0 {
-        boot_serial_send('0' as u8);
-    } else {
-        loop {
-            if num == 0 {
-                break;
-            }
-            msg[cnt] = match (num & 0xf) as u8 {
-                n if n < 10 => n + '0' as u8,
-                n => n - 10 + 'a' as u8,
-            };
-            cnt += 1;
-            num >>= 4;
+/// Prints `num` in hexadecimal with a `0x` prefix, without a newline.
+pub fn boot_print_hex(num: usize) {
+    let mut console = BootConsole::lock();
+    console.put_str("0x");
+    console.put_digits(num, 16);
+}
+
+/// Prints `num` in decimal, without a newline.
+pub fn boot_print_dec(num: usize) {
+    BootConsole::lock().put_digits(num, 10);
+}
+
+/// Formats `num` in `radix` at the end of `buf`, returns the digits.
+fn format_digits(mut num: usize, radix: usize, buf: &mut [u8; 20]) -> &[u8] {
+    // 64 bits need at most 20 decimal digits
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = match (num % radix) as u8 {
+            n if n < 10 => n + b'0',
+            n => n - 10 + b'a',
+        };
+        num /= radix;
+        if num == 0 {
+            break;
         }
-        for i in 0..cnt {
-            boot_serial_send(msg[cnt - i - 1]);
+    }
+    &buf[start..]
+}
+
+/// Returns the offset to add to a pointer stored in the image to get where
+/// it points to, 0 once the stored pointers are valid.
+///
+/// `core::fmt` reads the format pieces and the vtables through such
+/// pointers. They hold link-time addresses in the linear mapping, so they
+/// are only valid once the MMU is enabled (and the image relocated with the
+/// `pie` feature), unless the image runs at its link address.
+fn image_pointer_offset() -> usize {
+    static TARGET: u8 = 0;
+    static POINTER: &u8 = &TARGET;
+    // `&raw const` is PC-relative, the value of `POINTER` is absolute.
+    let linked = unsafe { (&raw const POINTER).read_volatile() } as *const u8 as usize;
+    (&raw const TARGET as usize).wrapping_sub(linked)
+}
+
+// Not inlined, so that the pieces of `args` are read from the image.
+#[doc(hidden)]
+#[inline(never)]
+pub fn _boot_print(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    let offset = image_pointer_offset();
+    if offset == 0 {
+        // The whole message is printed with the lock held.
+        let _ = BootConsole::lock().write_fmt(args);
+        return;
+    }
+    // The formatting code calls `write_str` through a vtable: only a message
+    // without arguments can be printed, with its pointer moved by hand.
+    match args.as_str() {
+        Some(msg) => {
+            let ptr = (msg.as_ptr() as usize).wrapping_add(offset) as *const u8;
+            let bytes = unsafe { core::slice::from_raw_parts(ptr, msg.len()) };
+            boot_print_str(unsafe { core::str::from_utf8_unchecked(bytes) });
         }
+        None => boot_print_str("[boot_print! unavailable pre-MMU]\r\n"),
     }
-    boot_print_str("\r\n");
+}
+
+/// Prints to the boot UART with `core::fmt`, without allocating.
+///
+/// Usable before the logger is initialized. Before the MMU is enabled, only
+/// messages without arguments are printed; the others are replaced by a
+/// `[boot_print! unavailable pre-MMU]` line, use the `boot_print_*`
+/// functions there.
+#[macro_export]
+macro_rules! boot_print {
+    ($($arg:tt)*) => {
+        $crate::_boot_print(format_args!($($arg)*))
+    };
+}
+
+/// Prints to the boot UART with `core::fmt`, with a newline.
+///
+/// Same restrictions as [`boot_print!`] before the MMU is enabled.
+#[macro_export]
+macro_rules! boot_println {
+    () => {
+        $crate::boot_print!("\r\n")
+    };
+    ($fmt:literal $($arg:tt)*) => {
+        $crate::_boot_print(format_args!(concat!($fmt, "\r\n") $($arg)*))
+    };
 }
 
 /// 打印字符串
 #[unsafe(no_mangle)]
 pub fn boot_print_str(data: &str) {
-    for byte in data.bytes() {
-        boot_serial_send(byte);
-    }
+    BootConsole::lock().put_str(data);
 }
 
 /// 打印整形
@@ -45,8 +125,47 @@ pub fn boot_print_usize(num: usize) {
     _boot_print_usize(num);
 }
 
+/// Receive buffer / transmit holding register.
+const UART_RBR_THR: usize = 0;
+/// Interrupt enable register.
+const UART_IER: usize = 1;
+/// FIFO control register (write only).
+const UART_FCR: usize = 2;
+/// Line control register.
+const UART_LCR: usize = 3;
+/// Modem control register.
+const UART_MCR: usize = 4;
+/// Line status register.
+const UART_LSR: usize = 5;
+/// Divisor latch, low and high bytes (with `LCR_DLAB`).
+const UART_DLL: usize = 0;
+const UART_DLM: usize = 1;
+
+/// IER: received data available interrupt.
+const IER_ERBFI: u8 = 1 << 0;
+/// IER: transmitter holding register empty interrupt.
+const IER_ETBEI: u8 = 1 << 1;
+
+/// LSR: data ready.
+const LSR_DR: u8 = 1 << 0;
+/// LSR: transmit holding register empty.
+const LSR_THRE: u8 = 1 << 5;
+
+/// FCR: enable the FIFOs and clear them.
+const FCR_FIFO_ENABLE_CLEAR: u8 = 0x07;
+/// LCR: 8 data bits, no parity, 1 stop bit.
+const LCR_8N1: u8 = 0x03;
+/// LCR: divisor latch access.
+const LCR_DLAB: u8 = 0x80;
+/// MCR: DTR and RTS.
+const MCR_DTR_RTS: u8 = 0x03;
+
+/// Polls of LSR before giving up on a character, so that a missing or
+/// stuck UART does not hang the boot.
+const UART_TX_TIMEOUT: usize = 1_000_000;
+
+/// NS16550A UART with byte-wide registers.
 #[derive(Copy, Clone, Debug)]
-/// Struct representing a NS16550A UART peripheral
 pub struct Uart {
 	/// Base address of the peripheral
 	base_address: usize,
@@ -58,17 +177,265 @@ impl Uart {
 		Self { base_address }
 	}
 
-	/// If the transmitter holding register is empty, writes `c` in the transmitter holding register, and returns `c`. Otherwise returns `None`.
+	fn read(&self, reg: usize) -> u8 {
+		unsafe { ((self.base_address + reg) as *const u8).read_volatile() }
+	}
+
+	fn write(&self, reg: usize, val: u8) {
+		unsafe { ((self.base_address + reg) as *mut u8).write_volatile(val) }
+	}
+
+	/// Enables and clears the FIFOs, selects 8N1 and masks the interrupts.
+	///
+	/// The baud rate is left as set by the firmware or the hypervisor.
+	pub fn init(&self) {
+		self.write(UART_IER, 0);
+		self.write(UART_LCR, LCR_8N1);
+		self.write(UART_FCR, FCR_FIFO_ENABLE_CLEAR);
+		self.write(UART_MCR, MCR_DTR_RTS);
+	}
+
+	/// Sets the baud rate from the input clock frequency.
+	pub fn set_baud(&self, clock: usize, baud: usize) {
+		let divisor = clock / (16 * baud.max(1));
+		if divisor == 0 || divisor > 0xffff {
+			return;
+		}
+		self.write(UART_LCR, LCR_8N1 | LCR_DLAB);
+		self.write(UART_DLL, divisor as u8);
+		self.write(UART_DLM, (divisor >> 8) as u8);
+		self.write(UART_LCR, LCR_8N1);
+	}
+
+	/// Waits for the transmitter holding register to be empty, then writes
+	/// `c` in it and returns `c`. Returns `None` if it stays busy.
 	pub fn put(&self, c: u8) -> Option<u8> {
-		let ptr = self.base_address as *mut u8;
-		unsafe {
-			ptr.write_volatile(c);
+		for _ in 0..UART_TX_TIMEOUT {
+			if self.read(UART_LSR) & LSR_THRE != 0 {
+				self.write(UART_RBR_THR, c);
+				return Some(c);
+			}
+			core::hint::spin_loop();
+		}
+		None
+	}
+
+	/// Writes `c` if the transmitter holding register is empty, without
+	/// waiting. Returns whether it was written.
+	#[allow(dead_code)]
+	pub fn try_put(&self, c: u8) -> bool {
+		let ready = self.read(UART_LSR) & LSR_THRE != 0;
+		if ready {
+			self.write(UART_RBR_THR, c);
 		}
-		Some(c)
+		ready
+	}
+
+	/// Enables or disables the received data available and the transmitter
+	/// holding register empty interrupts.
+	#[allow(dead_code)]
+	pub fn set_irqs(&self, rx: bool, tx: bool) {
+		let rx = if rx { IER_ERBFI } else { 0 };
+		let tx = if tx { IER_ETBEI } else { 0 };
+		self.write(UART_IER, rx | tx);
+	}
+
+	/// Reads a received character, if any.
+	pub fn get(&self) -> Option<u8> {
+		(self.read(UART_LSR) & LSR_DR != 0).then(|| self.read(UART_RBR_THR))
 	}
 }
 
-static BOOT_SERIAL: Uart = Uart::new(0x3f8);
+/// Physical address of the boot UART, `UART_PADDR` unless the device tree
+/// describes another one.
+#[unsafe(link_section = ".data")]
+static BOOT_UART_PADDR: AtomicUsize = AtomicUsize::new(UART_PADDR);
+
+/// Returns the physical address of the boot UART.
+pub fn boot_uart_paddr() -> usize {
+    BOOT_UART_PADDR.load(Ordering::Relaxed)
+}
+
+/// Returns the boot UART, through its physical address before the MMU is
+/// enabled and through the linear mapping afterwards.
+pub(crate) fn boot_uart() -> Uart {
+    uart_at(boot_uart_paddr())
+}
+
+/// Returns the UART at `paddr`, accessed like the boot UART.
+pub(crate) fn uart_at(paddr: usize) -> Uart {
+    let sctlr: u64;
+    unsafe { core::arch::asm!("mrs {}, sctlr_el1", out(reg) sctlr) };
+    if sctlr & 1 == 0 {
+        Uart::new(paddr)
+    } else {
+        Uart::new(paddr + crate::kaslr::phys_virt_offset())
+    }
+}
+
+/// IRQ of the boot UART from the device tree, 0 if not described.
+#[unsafe(link_section = ".data")]
+static BOOT_UART_IRQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the IRQ of the boot UART, `UART_IRQ` unless the device tree
+/// describes another one.
+pub fn boot_uart_irq() -> usize {
+    match BOOT_UART_IRQ.load(Ordering::Relaxed) {
+        0 => UART_IRQ,
+        irq => irq,
+    }
+}
+
+/// Longest `stdout-path` handled, and deepest node.
+const MAX_PATH: usize = 128;
+const MAX_PATH_DEPTH: usize = 8;
+
+/// A 16550 node of the device tree.
+pub(crate) struct UartNode {
+    pub paddr: usize,
+    pub irq: Option<usize>,
+    pub clock: Option<usize>,
+}
+
+impl UartNode {
+    /// Reads a node if it is an enabled 16550 UART.
+    pub fn from_node(node: &EarlyNode<'_>) -> Option<Self> {
+        let compatible = node.prop("compatible")?;
+        if !node.is_enabled()
+            || !(compatible.str_list_contains("ns16550a") || compatible.str_list_contains("ns16550"))
+        {
+            return None;
+        }
+        let (paddr, _) = node.reg().next()?;
+        // GIC interrupt specifier: type (0 = SPI, 1 = PPI), number, flags
+        let irq = node.prop("interrupts").and_then(|p| match (p.u32(0)?, p.u32(1)?) {
+            (0, num) => Some(num as usize + 32),
+            (1, num) => Some(num as usize + 16),
+            _ => None,
+        });
+        let clock = node.prop("clock-frequency").and_then(|p| p.u32(0)).map(|c| c as usize);
+        Some(Self { paddr, irq, clock })
+    }
+}
+
+/// Parses the decimal number at the start of `s`.
+fn parse_decimal(s: &[u8]) -> Option<usize> {
+    let digits = s.iter().take_while(|b| b.is_ascii_digit());
+    let mut val = None;
+    for &b in digits {
+        val = Some(val.unwrap_or(0) * 10 + (b - b'0') as usize);
+    }
+    val
+}
+
+/// Reads `/chosen/stdout-path` into `path`, resolving aliases through
+/// `/aliases` into `resolved`.
+///
+/// Returns the path, and the baud rate from the options after the `:` if
+/// any (e.g. `serial0:115200n8`).
+fn stdout_path<'a>(
+    fdt: &EarlyFdt,
+    path: &'a mut [u8; MAX_PATH],
+    resolved: &'a mut [u8; MAX_PATH],
+) -> Option<(&'a [u8], Option<usize>)> {
+    let mut len = 0;
+    fdt.walk(|node| {
+        if node.depth == 1 && node.name_matches(b"chosen") {
+            if let Some(prop) = node.prop("stdout-path").or_else(|| node.prop("linux,stdout-path")) {
+                len = prop.copy_str(path);
+            }
+        }
+    });
+    let mut baud = None;
+    if let Some(colon) = path[..len].iter().position(|&b| b == b':') {
+        baud = parse_decimal(&path[colon + 1..len]);
+        len = colon;
+    }
+    if len == 0 {
+        return None;
+    }
+    if path[0] == b'/' {
+        return Some((&path[..len], baud));
+    }
+
+    let alias = core::str::from_utf8(&path[..len]).ok()?;
+    let mut resolved_len = 0;
+    fdt.walk(|node| {
+        if node.depth == 1 && node.name_matches(b"aliases") {
+            if let Some(prop) = node.prop(alias) {
+                resolved_len = prop.copy_str(resolved);
+            }
+        }
+    });
+    if resolved_len == 0 || resolved[0] != b'/' {
+        return None;
+    }
+    Some((&resolved[..resolved_len], baud))
+}
+
+/// Finds the node at `path`.
+fn find_uart_by_path(fdt: &EarlyFdt, path: &[u8]) -> Option<UartNode> {
+    let mut components: [&[u8]; MAX_PATH_DEPTH] = [&[]; MAX_PATH_DEPTH];
+    let mut depth = 0;
+    for component in path.split(|&b| b == b'/').filter(|c| !c.is_empty()) {
+        *components.get_mut(depth)? = component;
+        depth += 1;
+    }
+    if depth == 0 {
+        return None;
+    }
+
+    // number of path components matched by the ancestors of the node
+    let mut matched = 0;
+    let mut found = None;
+    fdt.walk(|node| {
+        if found.is_some() || node.depth == 0 || node.depth > depth {
+            return;
+        }
+        matched = matched.min(node.depth - 1);
+        if matched == node.depth - 1 && node.name_matches(components[node.depth - 1]) {
+            matched = node.depth;
+            if node.depth == depth {
+                found = UartNode::from_node(node);
+            }
+        }
+    });
+    found
+}
+
+/// Looks for the console UART in the device tree and initializes it.
+///
+/// Called by the primary CPU before the MMU is enabled. The UART is the one
+/// `/chosen/stdout-path` points to, or else the first enabled 16550 node.
+/// The configured `UART_PADDR` and `UART_IRQ` are kept if there is none.
+pub(crate) fn init_boot_uart(dtb: usize) {
+    let mut baud = None;
+    let mut uart = None;
+    if let Some(fdt) = unsafe { EarlyFdt::from_paddr(dtb) } {
+        let (mut path, mut resolved) = ([0; MAX_PATH], [0; MAX_PATH]);
+        if let Some((path, path_baud)) = stdout_path(&fdt, &mut path, &mut resolved) {
+            uart = find_uart_by_path(&fdt, path);
+            baud = path_baud;
+        }
+        if uart.is_none() {
+            fdt.walk(|node| {
+                if uart.is_none() {
+                    uart = UartNode::from_node(node);
+                }
+            });
+        }
+    }
+    if let Some(node) = &uart {
+        BOOT_UART_PADDR.store(node.paddr, Ordering::Relaxed);
+        BOOT_UART_IRQ.store(node.irq.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    let boot_uart = boot_uart();
+    boot_uart.init();
+    if let (Some(baud), Some(clock)) = (baud, uart.and_then(|node| node.clock)) {
+        boot_uart.set_baud(clock, baud);
+    }
+}
 
 #[allow(dead_code)]
 /// 打印EL1寄存器
@@ -125,8 +492,113 @@ macro_rules! boot_print_reg {
     };
 }
 
+/// Serializes the boot console between CPUs.
+// Used before `.bss` is cleared.
+#[unsafe(link_section = ".data")]
+static BOOT_CONSOLE_LOCK: BakeryLock = BakeryLock::new();
+
+/// CPU id + 1 of the CPU whose line is being printed, 0 at the start of a
+/// line. Only accessed with the boot console lock held.
+#[unsafe(link_section = ".data")]
+static BOOT_CONSOLE_LINE: SyncCell = SyncCell::new(0);
+
+/// Returns the CPU id derived from `MPIDR_EL1` (affinity 0).
+fn boot_cpu_id() -> usize {
+    let mpidr: usize;
+    unsafe { core::arch::asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    mpidr & 0xff
+}
+
+/// The boot console, locked for the current CPU until dropped.
+///
+/// Each `boot_print_*` call takes the lock once and writes its bytes with
+/// it held, so that the messages of different CPUs are not mixed. Lines
+/// start with a `[cpuN] ` prefix, and a line left unfinished by another CPU
+/// is ended first.
+struct BootConsole {
+    cpu: usize,
+    /// The lock was taken by this guard, not by an outer call on this CPU.
+    locked: bool,
+    /// Cached `BOOT_CONSOLE_LINE`.
+    line: u32,
+}
+
+impl BootConsole {
+    fn lock() -> Self {
+        let cpu = boot_cpu_id();
+        // A CPU without a slot prints unserialized.
+        let locked = cpu < CPU_NUM && BOOT_CONSOLE_LOCK.lock(cpu);
+        Self {
+            cpu,
+            locked,
+            line: BOOT_CONSOLE_LINE.get(),
+        }
+    }
+
+    /// Sends `data` as is.
+    fn send(&self, data: u8) {
+        crate::early_log::push(data);
+        boot_uart().put(data);
+    }
+
+    fn put(&mut self, data: u8) {
+        let me = self.cpu as u32 + 1;
+        if self.line != 0 && self.line != me {
+            self.send(b'\r');
+            self.send(b'\n');
+            self.line = 0;
+        }
+        if self.line == 0 {
+            let mut buf = [0; 20];
+            b"[cpu".iter().for_each(|&b| self.send(b));
+            format_digits(self.cpu, 10, &mut buf)
+                .iter()
+                .for_each(|&b| self.send(b));
+            b"] ".iter().for_each(|&b| self.send(b));
+            self.line = me;
+        }
+        self.send(data);
+        if data == b'\n' {
+            self.line = 0;
+        }
+    }
+
+    fn put_str(&mut self, data: &str) {
+        data.bytes().for_each(|b| self.put(b));
+    }
+
+    fn put_digits(&mut self, num: usize, radix: usize) {
+        let mut buf = [0; 20];
+        format_digits(num, radix, &mut buf)
+            .iter()
+            .for_each(|&b| self.put(b));
+    }
+}
+
+impl core::fmt::Write for BootConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.put_str(s);
+        Ok(())
+    }
+}
+
+impl Drop for BootConsole {
+    fn drop(&mut self) {
+        BOOT_CONSOLE_LINE.set(self.line);
+        if self.locked {
+            BOOT_CONSOLE_LOCK.unlock(self.cpu);
+        }
+    }
+}
+
+/// Runs `f` with the boot console lock held.
+pub(crate) fn with_boot_console<R>(f: impl FnOnce() -> R) -> R {
+    let _console = BootConsole::lock();
+    f()
+}
+
 /// 打印字节
 #[allow(unused)]
 pub fn boot_serial_send(data: u8) {
-    unsafe { BOOT_SERIAL.put(data) };
+    BootConsole::lock().put(data);
 }
@@ -0,0 +1,350 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Registry of the 16550 serial ports described by the device tree.
+//!
+//! crosvm exposes up to four legacy ports (0x3f8, 0x2f8, 0x3e8, 0x2e8). Each
+//! port found at `init_later` gets its own RX and TX queues. With the `irq`
+//! feature, the queues are filled and drained by the port interrupt handler,
+//! otherwise the ports are polled. Ports sharing an IRQ are all serviced by
+//! the handler of that IRQ.
+//!
+//! The port carrying the console is in the registry too: the console reads
+//! from its RX queue, but writes to it synchronously (see `console.rs`).
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use kspin::SpinNoIrq;
+
+use crate::fdt::early::EarlyFdt;
+use crate::serial::{Uart, UartNode, uart_at};
+
+/// Maximum number of serial ports in the registry.
+pub const MAX_SERIAL_PORTS: usize = 4;
+
+/// Size of each RX and TX queue.
+const QUEUE_SIZE: usize = 1024;
+
+/// Size of the 16550 TX FIFO.
+const TX_FIFO_SIZE: usize = 16;
+
+/// Single producer, single consumer ring buffer.
+struct Ring {
+    buf: UnsafeCell<[u8; QUEUE_SIZE]>,
+    /// Number of bytes pushed since boot.
+    head: AtomicUsize,
+    /// Number of bytes popped since boot.
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pushes a byte, returns `false` if the buffer is full.
+    fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) == QUEUE_SIZE {
+            return false;
+        }
+        unsafe { (*self.buf.get())[head % QUEUE_SIZE] = byte };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.buf.get())[tail % QUEUE_SIZE] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    #[cfg(feature = "irq")]
+    fn is_empty(&self) -> bool {
+        self.tail.load(Ordering::Relaxed) == self.head.load(Ordering::Acquire)
+    }
+}
+
+/// A 16550 serial port.
+pub struct SerialPort {
+    paddr: AtomicUsize,
+    /// IRQ of the port, 0 if unknown.
+    irq: AtomicUsize,
+    /// Whether the queues are serviced by the interrupt handler.
+    irq_enabled: AtomicBool,
+    rx: Ring,
+    tx: Ring,
+    /// Serializes the RX consumers.
+    rx_lock: SpinNoIrq<()>,
+    /// Serializes the TX producers, so that the TX queue has a single one.
+    /// Taken before `tx_lock`, never inside it.
+    tx_producer_lock: SpinNoIrq<()>,
+    /// Serializes the TX consumers (the interrupt handler and direct
+    /// writes) and the TX interrupt enable.
+    tx_lock: SpinNoIrq<()>,
+    rx_dropped: AtomicUsize,
+}
+
+impl SerialPort {
+    const fn new() -> Self {
+        Self {
+            paddr: AtomicUsize::new(0),
+            irq: AtomicUsize::new(0),
+            irq_enabled: AtomicBool::new(false),
+            rx: Ring::new(),
+            tx: Ring::new(),
+            rx_lock: SpinNoIrq::new(()),
+            tx_producer_lock: SpinNoIrq::new(()),
+            tx_lock: SpinNoIrq::new(()),
+            rx_dropped: AtomicUsize::new(0),
+        }
+    }
+
+    fn uart(&self) -> Uart {
+        uart_at(self.paddr())
+    }
+
+    /// Returns the physical address of the port.
+    pub fn paddr(&self) -> usize {
+        self.paddr.load(Ordering::Relaxed)
+    }
+
+    /// Returns the IRQ of the port, if described by the device tree.
+    pub fn irq(&self) -> Option<usize> {
+        match self.irq.load(Ordering::Relaxed) {
+            0 => None,
+            irq => Some(irq),
+        }
+    }
+
+    /// Returns whether the port is the console.
+    pub fn is_console(&self) -> bool {
+        self.paddr() == crate::serial::boot_uart_paddr()
+    }
+
+    /// Returns the number of received bytes dropped because the RX queue
+    /// was full.
+    pub fn rx_dropped(&self) -> usize {
+        self.rx_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Moves the received bytes to the RX queue, and the TX queue to the
+    /// TX FIFO.
+    #[cfg(feature = "irq")]
+    fn handle_irq(&self) {
+        let uart = self.uart();
+        while let Some(byte) = uart.get() {
+            if !self.rx.push(byte) {
+                self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let _guard = self.tx_lock.lock();
+        for _ in 0..TX_FIFO_SIZE {
+            let Some(byte) = self.tx.pop() else {
+                break;
+            };
+            if !uart.try_put(byte) {
+                // should not happen, THRE was signaled
+                uart.put(byte);
+            }
+        }
+        uart.set_irqs(true, !self.tx.is_empty());
+    }
+
+    /// Reads the received bytes into `buf` without blocking.
+    ///
+    /// Returns the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let _guard = self.rx_lock.lock();
+        let irq = self.irq_enabled.load(Ordering::Acquire);
+        let uart = self.uart();
+        let mut len = 0;
+        while len < buf.len() {
+            let byte = if irq { self.rx.pop() } else { uart.get() };
+            match byte {
+                Some(byte) => buf[len] = byte,
+                None => break,
+            }
+            len += 1;
+        }
+        len
+    }
+
+    /// Reads at least one byte into `buf`, waiting for interrupts in between.
+    ///
+    /// Returns the number of bytes read, 0 only if `buf` is empty.
+    pub fn read_blocking(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        loop {
+            let len = self.read(buf);
+            if len > 0 {
+                return len;
+            }
+            if self.irq_enabled.load(Ordering::Acquire) {
+                aarch64_cpu::asm::wfi();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Queues `bytes` for transmission.
+    ///
+    /// Writes the queue out directly when it is full. Without an interrupt,
+    /// the bytes are written directly.
+    pub fn write(&self, bytes: &[u8]) {
+        if !self.irq_enabled.load(Ordering::Acquire) {
+            let uart = self.uart();
+            let _guard = self.tx_lock.lock();
+            for &byte in bytes {
+                uart.put(byte);
+            }
+            return;
+        }
+        let _producer = self.tx_producer_lock.lock();
+        for &byte in bytes {
+            while !self.tx.push(byte) {
+                // Full. Interrupts are masked while holding the lock and
+                // may be routed to this CPU, so drain it here.
+                self.drain_tx();
+            }
+        }
+        self.kick_tx();
+    }
+
+    /// Writes the TX queue to the UART, polling the FIFO.
+    fn drain_tx(&self) {
+        let _guard = self.tx_lock.lock();
+        let uart = self.uart();
+        while let Some(byte) = self.tx.pop() {
+            uart.put(byte);
+        }
+    }
+
+    /// Enables the TX interrupt, which fires as soon as the FIFO is empty.
+    fn kick_tx(&self) {
+        let _guard = self.tx_lock.lock();
+        self.uart().set_irqs(true, true);
+    }
+}
+
+static PORTS: [SerialPort; MAX_SERIAL_PORTS] = [const { SerialPort::new() }; MAX_SERIAL_PORTS];
+static PORT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the serial ports found in the device tree, in device tree order.
+pub fn serial_ports() -> &'static [SerialPort] {
+    &PORTS[..PORT_COUNT.load(Ordering::Acquire)]
+}
+
+/// Returns the `idx`-th serial port.
+pub fn serial_port(idx: usize) -> Option<&'static SerialPort> {
+    serial_ports().get(idx)
+}
+
+/// Returns the serial port carrying the console.
+pub fn console_port() -> Option<&'static SerialPort> {
+    serial_ports().iter().find(|port| port.is_console())
+}
+
+/// Fills the registry from the device tree.
+///
+/// Called by the primary CPU in `init_later`. The console port is always
+/// registered, even if the device tree does not describe it.
+pub(crate) fn init(dtb_vaddr: usize) {
+    let mut count = 0;
+    let mut add = |paddr: usize, irq: Option<usize>| {
+        if count == MAX_SERIAL_PORTS || PORTS[..count].iter().any(|p| p.paddr() == paddr) {
+            return;
+        }
+        PORTS[count].paddr.store(paddr, Ordering::Relaxed);
+        PORTS[count].irq.store(irq.unwrap_or(0), Ordering::Relaxed);
+        PORTS[count].uart().init();
+        count += 1;
+    };
+    if let Some(fdt) = unsafe { EarlyFdt::from_paddr(dtb_vaddr) } {
+        fdt.walk(|node| {
+            if let Some(uart) = UartNode::from_node(node) {
+                add(uart.paddr, uart.irq);
+            }
+        });
+    }
+    add(crate::serial::boot_uart_paddr(), Some(crate::serial::boot_uart_irq()));
+    PORT_COUNT.store(count, Ordering::Release);
+
+    for port in serial_ports() {
+        log::info!(
+            "serial port {:#x} IRQ {:?}{}",
+            port.paddr(),
+            port.irq(),
+            if port.is_console() { " (console)" } else { "" }
+        );
+    }
+}
+
+/// Services every port on `irq`.
+#[cfg(feature = "irq")]
+fn handle_irq_line(irq: usize) {
+    for port in serial_ports().iter().filter(|port| port.irq() == Some(irq)) {
+        port.handle_irq();
+    }
+}
+
+#[cfg(feature = "irq")]
+fn port_irq_handler<const N: usize>() {
+    if let Some(irq) = PORTS[N].irq() {
+        handle_irq_line(irq);
+    }
+}
+
+#[cfg(feature = "irq")]
+const PORT_IRQ_HANDLERS: [axplat::irq::IrqHandler; MAX_SERIAL_PORTS] = [
+    port_irq_handler::<0>,
+    port_irq_handler::<1>,
+    port_irq_handler::<2>,
+    port_irq_handler::<3>,
+];
+
+/// Registers the interrupt handlers of the ports and enables their RX
+/// interrupts.
+///
+/// Called by the primary CPU once the GIC is initialized.
+#[cfg(feature = "irq")]
+pub(crate) fn init_irqs() {
+    for (idx, port) in serial_ports().iter().enumerate() {
+        let Some(irq) = port.irq() else {
+            continue;
+        };
+        let first = serial_ports()[..idx].iter().all(|p| p.irq() != Some(irq));
+        if first {
+            // crosvm raises the serial interrupts as edges
+            crate::gicv3::set_trigger(irq, true);
+            if !crate::gicv3::register_handler(irq, PORT_IRQ_HANDLERS[idx]) {
+                log::warn!("failed to register the serial IRQ {}", irq);
+                continue;
+            }
+        } else if !serial_ports()[..idx]
+            .iter()
+            .any(|p| p.irq() == Some(irq) && p.irq_enabled.load(Ordering::Acquire))
+        {
+            continue;
+        }
+        port.irq_enabled.store(true, Ordering::Release);
+        port.uart().set_irqs(true, false);
+    }
+}
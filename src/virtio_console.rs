@@ -0,0 +1,534 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2025 WeiKang Guo <guoweikang.kernel@gmail.com
+// Copyright (C) 2025 KylinSoft Co., Ltd. <https://www.kylinos.cn/>
+// See LICENSE for license details.
+
+//! Console on a virtio-console device (`hvc`) found on the PCI bus.
+//!
+//! crosvm can route the console to a virtio-console device
+//! (`--serial ...,hardware=virtio-console`) instead of a 16550. [`init`]
+//! looks for the device on the root bus at `init_later`, and the console
+//! switches to it once it is ready. The boot UART stays the early console
+//! until then, and the fallback if no device is found or it stops
+//! answering.
+//!
+//! The driver is kept minimal: modern (virtio 1.x) PCI transport only, the
+//! first port only (no `VIRTIO_CONSOLE_F_MULTIPORT`) and polled queues. The
+//! queues and buffers live in a static, shared with the host under pKVM.
+
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicBool, Ordering, fence};
+
+use axplat::mem::{pa, phys_to_virt, va, virt_to_phys};
+use kspin::SpinNoIrq;
+
+use crate::config::devices::{PCI_BUS_END, PCI_ECAM_BASE, PCI_RANGES};
+
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+/// Modern virtio-console device ID (0x1040 + device type 3).
+const VIRTIO_CONSOLE_DEVICE_ID: u16 = 0x1043;
+/// Transitional virtio-console device ID.
+const VIRTIO_CONSOLE_TRANSITIONAL_ID: u16 = 0x1003;
+
+// PCI configuration space registers.
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_DEVICE_ID: usize = 0x02;
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_BAR0: usize = 0x10;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_COMMAND_MASTER: u16 = 1 << 2;
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+// `cfg_type` of the virtio vendor-specific capabilities.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+
+// Registers of the common configuration structure.
+const COMMON_DFSELECT: usize = 0x00;
+const COMMON_DF: usize = 0x04;
+const COMMON_GFSELECT: usize = 0x08;
+const COMMON_GF: usize = 0x0c;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_Q_SELECT: usize = 0x16;
+const COMMON_Q_SIZE: usize = 0x18;
+const COMMON_Q_ENABLE: usize = 0x1c;
+const COMMON_Q_NOFF: usize = 0x1e;
+const COMMON_Q_DESCLO: usize = 0x20;
+const COMMON_Q_DESCHI: usize = 0x24;
+const COMMON_Q_AVAILLO: usize = 0x28;
+const COMMON_Q_AVAILHI: usize = 0x2c;
+const COMMON_Q_USEDLO: usize = 0x30;
+const COMMON_Q_USEDHI: usize = 0x34;
+
+// Device status bits.
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+
+// Feature bits, relative to the upper 32 bits.
+const VIRTIO_F_VERSION_1: u32 = 1 << (32 - 32);
+const VIRTIO_F_ACCESS_PLATFORM: u32 = 1 << (33 - 32);
+
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Queue indices of port 0.
+const RECEIVEQ: u16 = 0;
+const TRANSMITQ: u16 = 1;
+
+/// Maximum size of each virtqueue.
+const QUEUE_SIZE: usize = 16;
+const RX_BUF_SIZE: usize = 64;
+const TX_BUF_SIZE: usize = 512;
+
+/// Number of polls of the used ring before a transmission is considered
+/// lost.
+const TX_TIMEOUT: usize = 10_000_000;
+
+#[repr(C)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C, align(4))]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+/// Split virtqueue.
+#[repr(C, align(16))]
+struct Virtqueue {
+    desc: [Desc; QUEUE_SIZE],
+    avail: AvailRing,
+    used: UsedRing,
+}
+
+/// Memory accessed by the device.
+///
+/// Aligned to the largest granule, so that sharing it with the host under
+/// pKVM does not expose anything else.
+#[repr(C, align(0x10000))]
+struct SharedMem {
+    rx: Virtqueue,
+    tx: Virtqueue,
+    rx_bufs: [[u8; RX_BUF_SIZE]; QUEUE_SIZE],
+    tx_buf: [u8; TX_BUF_SIZE],
+}
+
+static mut SHARED_MEM: SharedMem = unsafe { core::mem::zeroed() };
+
+/// Function 0 of a device in the ECAM space.
+struct PciFunction {
+    base: usize,
+}
+
+impl PciFunction {
+    fn new(bus: usize, dev: usize) -> Self {
+        let paddr = PCI_ECAM_BASE + (bus << 20) + (dev << 15);
+        Self {
+            base: phys_to_virt(pa!(paddr)).as_usize(),
+        }
+    }
+
+    fn read8(&self, off: usize) -> u8 {
+        unsafe { ((self.base + off) as *const u8).read_volatile() }
+    }
+
+    fn read16(&self, off: usize) -> u16 {
+        unsafe { ((self.base + off) as *const u16).read_volatile() }
+    }
+
+    fn read32(&self, off: usize) -> u32 {
+        unsafe { ((self.base + off) as *const u32).read_volatile() }
+    }
+
+    fn write16(&self, off: usize, val: u16) {
+        unsafe { ((self.base + off) as *mut u16).write_volatile(val) }
+    }
+
+    /// Returns the physical address of the memory BAR `bar`, if assigned
+    /// within the PCI memory window.
+    fn bar_paddr(&self, bar: u8) -> Option<usize> {
+        if bar > 5 {
+            return None;
+        }
+        let off = PCI_BAR0 + bar as usize * 4;
+        let lo = self.read32(off);
+        if lo & 1 != 0 {
+            // I/O space
+            return None;
+        }
+        let mut paddr = (lo & !0xf) as usize;
+        if (lo >> 1) & 3 == 2 && bar < 5 {
+            paddr |= (self.read32(off + 4) as usize) << 32;
+        }
+        PCI_RANGES
+            .iter()
+            .any(|&(base, size)| paddr != 0 && paddr >= base && paddr < base + size)
+            .then_some(paddr)
+    }
+
+    /// Returns the virtual address of the virtio structure of type
+    /// `cfg_type`, and the offset of the capability.
+    fn virtio_cap(&self, cfg_type: u8) -> Option<(usize, usize)> {
+        if self.read16(PCI_STATUS) & PCI_STATUS_CAP_LIST == 0 {
+            return None;
+        }
+        let mut cap = (self.read8(PCI_CAPABILITY_LIST) & !3) as usize;
+        while cap != 0 {
+            if self.read8(cap) == PCI_CAP_ID_VNDR && self.read8(cap + 3) == cfg_type {
+                let paddr = self.bar_paddr(self.read8(cap + 4))? + self.read32(cap + 8) as usize;
+                return Some((phys_to_virt(pa!(paddr)).as_usize(), cap));
+            }
+            cap = (self.read8(cap + 1) & !3) as usize;
+        }
+        None
+    }
+}
+
+fn mmio_read<T>(addr: usize) -> T {
+    unsafe { (addr as *const T).read_volatile() }
+}
+
+fn mmio_write<T>(addr: usize, val: T) {
+    unsafe { (addr as *mut T).write_volatile(val) }
+}
+
+/// Physical address of a field of the shared memory.
+fn shared_paddr<T>(ptr: *const T) -> u64 {
+    virt_to_phys(va!(ptr as usize)).as_usize() as u64
+}
+
+struct VirtioConsole {
+    common: usize,
+    notify_rx: usize,
+    notify_tx: usize,
+    rx_size: u16,
+    tx_size: u16,
+    mem: *mut SharedMem,
+    rx_avail_idx: u16,
+    rx_used_idx: u16,
+    tx_idx: u16,
+    /// Used RX buffer being consumed: descriptor, length and read offset.
+    rx_pending: Option<(u16, usize, usize)>,
+}
+
+unsafe impl Send for VirtioConsole {}
+
+impl VirtioConsole {
+    fn set_status(&self, status: u8) -> u8 {
+        mmio_write(self.common + COMMON_STATUS, status);
+        mmio_read(self.common + COMMON_STATUS)
+    }
+
+    /// Configures and enables a virtqueue, returns its size.
+    fn setup_queue(&self, index: u16, vq: *mut Virtqueue) -> Result<u16, &'static str> {
+        let common = self.common;
+        mmio_write(common + COMMON_Q_SELECT, index);
+        let max = mmio_read::<u16>(common + COMMON_Q_SIZE);
+        if max == 0 {
+            return Err("queue not available");
+        }
+        // Both are powers of two.
+        let size = max.min(QUEUE_SIZE as u16);
+        mmio_write(common + COMMON_Q_SIZE, size);
+        let (desc, avail, used) = unsafe {
+            (
+                shared_paddr(addr_of!((*vq).desc)),
+                shared_paddr(addr_of!((*vq).avail)),
+                shared_paddr(addr_of!((*vq).used)),
+            )
+        };
+        mmio_write(common + COMMON_Q_DESCLO, desc as u32);
+        mmio_write(common + COMMON_Q_DESCHI, (desc >> 32) as u32);
+        mmio_write(common + COMMON_Q_AVAILLO, avail as u32);
+        mmio_write(common + COMMON_Q_AVAILHI, (avail >> 32) as u32);
+        mmio_write(common + COMMON_Q_USEDLO, used as u32);
+        mmio_write(common + COMMON_Q_USEDHI, (used >> 32) as u32);
+        mmio_write(common + COMMON_Q_ENABLE, 1u16);
+        Ok(size)
+    }
+
+    /// Makes the RX buffer `id` available to the device.
+    fn post_rx(&mut self, id: u16) {
+        let rx = unsafe { addr_of_mut!((*self.mem).rx) };
+        unsafe {
+            addr_of_mut!((*rx).avail.ring[(self.rx_avail_idx % self.rx_size) as usize])
+                .write_volatile(id);
+            self.rx_avail_idx = self.rx_avail_idx.wrapping_add(1);
+            fence(Ordering::SeqCst);
+            addr_of_mut!((*rx).avail.idx).write_volatile(self.rx_avail_idx);
+        }
+        fence(Ordering::SeqCst);
+        mmio_write(self.notify_rx, RECEIVEQ);
+    }
+
+    /// Sends the first `len` bytes of the TX buffer and waits for the device
+    /// to consume them.
+    fn flush(&mut self, len: usize) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let mem = self.mem;
+        unsafe {
+            let desc = addr_of_mut!((*mem).tx.desc[0]);
+            addr_of_mut!((*desc).addr).write_volatile(shared_paddr(addr_of!((*mem).tx_buf)));
+            addr_of_mut!((*desc).len).write_volatile(len as u32);
+            addr_of_mut!((*desc).flags).write_volatile(0);
+            addr_of_mut!((*mem).tx.avail.ring[(self.tx_idx % self.tx_size) as usize])
+                .write_volatile(0);
+            self.tx_idx = self.tx_idx.wrapping_add(1);
+            fence(Ordering::SeqCst);
+            addr_of_mut!((*mem).tx.avail.idx).write_volatile(self.tx_idx);
+        }
+        fence(Ordering::SeqCst);
+        mmio_write(self.notify_tx, TRANSMITQ);
+        for _ in 0..TX_TIMEOUT {
+            if unsafe { addr_of!((*mem).tx.used.idx).read_volatile() } == self.tx_idx {
+                fence(Ordering::SeqCst);
+                return true;
+            }
+            core::hint::spin_loop();
+        }
+        false
+    }
+
+    /// Writes `bytes`, converting `\n` to `\r\n`. Returns `false` if the
+    /// device stopped consuming the TX queue.
+    fn write(&mut self, bytes: &[u8]) -> bool {
+        let buf = unsafe { &mut *addr_of_mut!((*self.mem).tx_buf) };
+        let mut len = 0;
+        for &byte in bytes {
+            if len + 2 > TX_BUF_SIZE {
+                if !self.flush(len) {
+                    return false;
+                }
+                len = 0;
+            }
+            if byte == b'\n' {
+                buf[len] = b'\r';
+                len += 1;
+            }
+            buf[len] = byte;
+            len += 1;
+        }
+        self.flush(len)
+    }
+
+    /// Reads the received bytes into `buf` without blocking.
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mem = self.mem;
+        let mut read = 0;
+        while read < buf.len() {
+            let (id, len, off) = match self.rx_pending {
+                Some(pending) => pending,
+                None => {
+                    let used_idx = unsafe { addr_of!((*mem).rx.used.idx).read_volatile() };
+                    if used_idx == self.rx_used_idx {
+                        break;
+                    }
+                    fence(Ordering::SeqCst);
+                    let slot = (self.rx_used_idx % self.rx_size) as usize;
+                    let elem = unsafe { addr_of!((*mem).rx.used.ring[slot]).read_volatile() };
+                    self.rx_used_idx = self.rx_used_idx.wrapping_add(1);
+                    (elem.id as u16, (elem.len as usize).min(RX_BUF_SIZE), 0)
+                }
+            };
+            let data = unsafe { &(*addr_of!((*mem).rx_bufs))[id as usize][off..len] };
+            let n = data.len().min(buf.len() - read);
+            buf[read..read + n].copy_from_slice(&data[..n]);
+            read += n;
+            if off + n == len {
+                self.rx_pending = None;
+                self.post_rx(id);
+            } else {
+                self.rx_pending = Some((id, len, off + n));
+            }
+        }
+        read
+    }
+}
+
+/// Sets up the device `func` as the console.
+fn probe(func: &PciFunction) -> Result<VirtioConsole, &'static str> {
+    let (common, _) = func
+        .virtio_cap(VIRTIO_PCI_CAP_COMMON_CFG)
+        .ok_or("no common configuration")?;
+    let (notify, notify_cap) = func
+        .virtio_cap(VIRTIO_PCI_CAP_NOTIFY_CFG)
+        .ok_or("no notification structure")?;
+    let notify_multiplier = func.read32(notify_cap + 16) as usize;
+    func.write16(
+        PCI_COMMAND,
+        func.read16(PCI_COMMAND) | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER,
+    );
+
+    let mem = &raw mut SHARED_MEM;
+    crate::psci::share_dma_buffer(
+        shared_paddr(mem.cast_const()) as usize,
+        size_of::<SharedMem>(),
+    );
+
+    let mut dev = VirtioConsole {
+        common,
+        notify_rx: 0,
+        notify_tx: 0,
+        rx_size: 0,
+        tx_size: 0,
+        mem,
+        rx_avail_idx: 0,
+        rx_used_idx: 0,
+        tx_idx: 0,
+        rx_pending: None,
+    };
+
+    dev.set_status(0);
+    while mmio_read::<u8>(common + COMMON_STATUS) != 0 {
+        core::hint::spin_loop();
+    }
+    let mut status = STATUS_ACKNOWLEDGE;
+    dev.set_status(status);
+    status |= STATUS_DRIVER;
+    dev.set_status(status);
+
+    mmio_write(common + COMMON_DFSELECT, 1u32);
+    let features = mmio_read::<u32>(common + COMMON_DF);
+    if features & VIRTIO_F_VERSION_1 == 0 {
+        dev.set_status(0);
+        return Err("legacy device");
+    }
+    mmio_write(common + COMMON_GFSELECT, 0u32);
+    mmio_write(common + COMMON_GF, 0u32);
+    mmio_write(common + COMMON_GFSELECT, 1u32);
+    mmio_write(
+        common + COMMON_GF,
+        features & (VIRTIO_F_VERSION_1 | VIRTIO_F_ACCESS_PLATFORM),
+    );
+    status |= STATUS_FEATURES_OK;
+    if dev.set_status(status) & STATUS_FEATURES_OK == 0 {
+        dev.set_status(0);
+        return Err("features not accepted");
+    }
+
+    let queues = unsafe { (addr_of_mut!((*mem).rx), addr_of_mut!((*mem).tx)) };
+    let setup = dev
+        .setup_queue(RECEIVEQ, queues.0)
+        .and_then(|rx| Ok((rx, dev.setup_queue(TRANSMITQ, queues.1)?)));
+    let (rx_size, tx_size) = match setup {
+        Ok(sizes) => sizes,
+        Err(err) => {
+            dev.set_status(0);
+            return Err(err);
+        }
+    };
+    dev.rx_size = rx_size;
+    dev.tx_size = tx_size;
+    for (queue, notify_addr) in [
+        (RECEIVEQ, &mut dev.notify_rx),
+        (TRANSMITQ, &mut dev.notify_tx),
+    ] {
+        mmio_write(common + COMMON_Q_SELECT, queue);
+        let off = mmio_read::<u16>(common + COMMON_Q_NOFF) as usize;
+        *notify_addr = notify + off * notify_multiplier;
+    }
+
+    status |= STATUS_DRIVER_OK;
+    dev.set_status(status);
+
+    for id in 0..rx_size {
+        unsafe {
+            let desc = addr_of_mut!((*mem).rx.desc[id as usize]);
+            let buf = addr_of!((*mem).rx_bufs[id as usize]);
+            addr_of_mut!((*desc).addr).write_volatile(shared_paddr(buf));
+            addr_of_mut!((*desc).len).write_volatile(RX_BUF_SIZE as u32);
+            addr_of_mut!((*desc).flags).write_volatile(VIRTQ_DESC_F_WRITE);
+        }
+        dev.post_rx(id);
+    }
+    Ok(dev)
+}
+
+static DEVICE: SpinNoIrq<Option<VirtioConsole>> = SpinNoIrq::new(None);
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Looks for a virtio-console device on the PCI bus and switches the console
+/// to the first one found.
+pub(crate) fn init() {
+    for bus in 0..=PCI_BUS_END {
+        for dev in 0..32 {
+            let func = PciFunction::new(bus, dev);
+            if func.read16(PCI_VENDOR_ID) != VIRTIO_VENDOR_ID {
+                continue;
+            }
+            let device_id = func.read16(PCI_DEVICE_ID);
+            if device_id != VIRTIO_CONSOLE_DEVICE_ID && device_id != VIRTIO_CONSOLE_TRANSITIONAL_ID
+            {
+                continue;
+            }
+            match probe(&func) {
+                Ok(console) => {
+                    *DEVICE.lock() = Some(console);
+                    READY.store(true, Ordering::Release);
+                    log::info!("console on virtio-console {:02x}:{:02x}.0", bus, dev);
+                    return;
+                }
+                Err(err) => log::warn!("virtio-console {:02x}:{:02x}.0: {}", bus, dev, err),
+            }
+        }
+    }
+    log::info!("no virtio-console device, console stays on the UART");
+}
+
+/// Returns whether the console is on the virtio-console device.
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Writes bytes to the device, converting `\n` to `\r\n`.
+///
+/// Returns `false` if the device is not ready or stopped consuming the TX
+/// queue, in which case the console goes back to the UART.
+pub fn write(bytes: &[u8]) -> bool {
+    let mut device = DEVICE.lock();
+    let Some(dev) = device.as_mut() else {
+        return false;
+    };
+    if dev.write(bytes) {
+        return true;
+    }
+    *device = None;
+    READY.store(false, Ordering::Release);
+    false
+}
+
+/// Reads the received bytes into `buf` without blocking.
+///
+/// Returns the number of bytes read.
+pub fn read(buf: &mut [u8]) -> usize {
+    DEVICE.lock().as_mut().map_or(0, |dev| dev.read(buf))
+}